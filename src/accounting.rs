@@ -0,0 +1,761 @@
+//! Per-block accounting of P2PK outputs and the other tracked totals.
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::Result;
+use bitcoin::{
+    hashes::{sha256, Hash},
+    Block, OutPoint, PublicKey, TxOut, Txid,
+};
+
+use crate::script::{
+    classify_script, is_bare_multisig, op_return_payload, p2pk_pubkey_bytes, ScriptType,
+};
+
+/// How the unique P2PK key column counts keys.
+#[derive(Clone, Copy)]
+pub enum UniqueKeyMode {
+    /// Every key that has received a P2PK output at least once
+    Ever,
+    /// Keys that still hold at least one unspent P2PK output
+    Current,
+}
+
+/// Settings that decide which P2PK outputs count towards the totals. Changing any of them changes
+/// what the totals mean.
+#[derive(Default)]
+pub struct P2pkRules {
+    /// Also accept keys pushed with `OP_PUSHDATA1`
+    pub loose: bool,
+    /// Leave out outputs created by coinbase transactions
+    pub exclude_coinbase: bool,
+    /// Only count outputs of at least this many sats
+    pub min_value: Option<u64>,
+    /// Only count outputs of at most this many sats
+    pub max_value: Option<u64>,
+    /// Keys whose coins are known to be destroyed, counted as burned rather than as P2PK
+    pub burn_keys: HashSet<Vec<u8>>,
+}
+
+impl P2pkRules {
+    /// Returns the key of a P2PK output that passes the coinbase and value filters, whether or not
+    /// it is burn-listed. The same test applies when the output is created and when it's spent.
+    pub fn p2pk_key<'a>(&self, output: &'a TxOut, from_coinbase: bool) -> Option<&'a [u8]> {
+        if self.exclude_coinbase && from_coinbase {
+            return None;
+        }
+        let sats = output.value.to_sat();
+        p2pk_pubkey_bytes(&output.script_pubkey, self.loose).filter(|_| {
+            self.min_value.is_none_or(|min| sats >= min)
+                && self.max_value.is_none_or(|max| sats <= max)
+        })
+    }
+
+    /// Describes the rules for the `# settings=` line of the output, so a file is only resumed
+    /// under the rules it was written with. The burn list is identified by a hash of its keys.
+    pub fn settings(&self) -> String {
+        let mut burn_keys: Vec<&[u8]> = self.burn_keys.iter().map(Vec::as_slice).collect();
+        burn_keys.sort();
+        let burn_list = if burn_keys.is_empty() {
+            "none".to_owned()
+        } else {
+            sha256::Hash::hash(&burn_keys.concat()).to_string()
+        };
+        let bound = |value: Option<u64>| value.map_or("none".to_owned(), |v| v.to_string());
+        format!(
+            "loose_p2pk={},exclude_coinbase_p2pk={},min_value={},max_value={},burn_list={burn_list}",
+            self.loose,
+            self.exclude_coinbase,
+            bound(self.min_value),
+            bound(self.max_value)
+        )
+    }
+
+    /// Returns the key of a P2PK output if it counts towards the P2PK totals.
+    pub fn counted_key<'a>(&self, output: &'a TxOut, from_coinbase: bool) -> Option<&'a [u8]> {
+        self.p2pk_key(output, from_coinbase)
+            .filter(|key| !self.burn_keys.contains(*key))
+    }
+}
+
+/// How blocks are accounted for, besides the P2PK rules.
+pub struct Accounting {
+    pub rules: P2pkRules,
+    pub unique_key_mode: Option<UniqueKeyMode>,
+    /// Check every P2PK key is a point on the curve, which is slow
+    pub track_unspendable: bool,
+    /// Keep the outputs behind the counts in [`BlockCounts`], for dumps and traces
+    pub details: bool,
+}
+
+/// Cumulative totals carried from block to block.
+#[derive(Default)]
+pub struct Totals {
+    pub p2pk_addresses: i64,
+    pub p2pk_coins: f64,
+    /// Sats sent to OP_RETURN outputs
+    pub burned_sats: u64,
+    /// P2PK outputs to keys that aren't points on the curve
+    pub unspendable_outputs: u64,
+    pub unspendable_coins: f64,
+    /// P2PK coins sent to burn-listed keys
+    pub burned_p2pk_coins: f64,
+    /// Value of every output created, which can exceed u64 once summed over the whole chain
+    pub total_sats_added: u128,
+    /// Unspent bare multisig outputs
+    pub multisig_outputs: i64,
+    pub multisig_coins: f64,
+    /// Unspent P2SH outputs, counted as the outputs themselves whatever their redeem script is
+    pub p2sh_outputs: i64,
+    pub p2sh_coins: f64,
+    pub total_transactions: u64,
+    pub total_inputs: u64,
+    pub total_outputs: u64,
+    pub total_segwit_transactions: u64,
+    pub total_non_coinbase_transactions: u64,
+    /// Unspent P2PK outputs per public key seen during this run. In current mode, keys are removed
+    /// once all their outputs are spent so the map only holds the live key set.
+    pub key_outputs: HashMap<Vec<u8>, u64>,
+}
+
+/// Counts for a single block.
+#[derive(Default)]
+pub struct BlockCounts {
+    pub p2pk_outputs_created: u64,
+    pub p2pk_outputs_spent: u64,
+    pub p2pk_sats_added: u64,
+    pub p2pk_sats_spent: u64,
+    /// P2PK outputs by key encoding: 33-byte compressed or 65-byte uncompressed
+    pub compressed_created: u64,
+    pub compressed_spent: u64,
+    pub uncompressed_created: u64,
+    pub uncompressed_spent: u64,
+    pub p2tr_outputs_created: u64,
+    pub p2tr_outputs_spent: u64,
+    /// Public keys exposed by bare multisig outputs, indexed by key count - 1
+    pub multisig_keys_exposed: [u64; 3],
+    /// Value of all outputs created
+    pub sats_added: u128,
+    pub transactions: u64,
+    pub inputs: u64,
+    pub outputs: u64,
+    /// Transactions other than the coinbase, whose witness only carries the commitment's reserved
+    /// value, and those of them spending at least one input with witness data
+    pub non_coinbase_transactions: u64,
+    pub segwit_transactions: u64,
+    /// Whether this block took the P2PK address total below zero. Every spend follows the output
+    /// it spends, so this means a block was miscounted, here or earlier.
+    pub went_negative: bool,
+    /// With details on, the P2PK outputs counted as created
+    pub p2pk_created: Vec<(OutPoint, TxOut)>,
+    /// With details on, the P2PK outputs counted as spent, and the transactions spending them
+    pub p2pk_spent: Vec<(Txid, OutPoint, TxOut)>,
+    /// With details on, the payload of each OP_RETURN output and its transaction
+    pub op_returns: Vec<(Txid, Vec<u8>)>,
+}
+
+impl Accounting {
+    /// Adds a block to `totals` and returns its own counts.
+    ///
+    /// `prevout` looks up the output an input spends, and whether a coinbase created it.
+    pub fn account_block(
+        &self,
+        block: &Block,
+        totals: &mut Totals,
+        mut prevout: impl FnMut(&OutPoint) -> Result<Option<(TxOut, bool)>>,
+    ) -> Result<BlockCounts> {
+        let rules = &self.rules;
+        let mut counts = BlockCounts::default();
+        let p2pk_addresses_before = totals.p2pk_addresses;
+
+        for tx in &block.txdata {
+            // Hash the transaction at most once, and only if a detail needs it
+            let mut txid = None;
+
+            // Account for the new coins
+            for (vout, output) in tx.output.iter().enumerate() {
+                let p2pk_key = rules.p2pk_key(output, tx.is_coinbase());
+                if p2pk_key.is_some_and(|key| rules.burn_keys.contains(key)) {
+                    totals.burned_p2pk_coins += output.value.to_btc();
+                } else if let Some(key) = p2pk_key {
+                    totals.p2pk_addresses += 1;
+                    totals.p2pk_coins += output.value.to_btc();
+                    counts.p2pk_outputs_created += 1;
+                    counts.p2pk_sats_added += output.value.to_sat();
+                    if key.len() == 33 {
+                        counts.compressed_created += 1;
+                    } else {
+                        counts.uncompressed_created += 1;
+                    }
+
+                    if self.unique_key_mode.is_some() {
+                        *totals.key_outputs.entry(key.to_vec()).or_default() += 1;
+                    }
+
+                    // A key that isn't a point on the curve can never sign, so these coins are gone
+                    if self.track_unspendable && PublicKey::from_slice(key).is_err() {
+                        totals.unspendable_outputs += 1;
+                        totals.unspendable_coins += output.value.to_btc();
+                    }
+
+                    if self.details {
+                        let txid = *txid.get_or_insert_with(|| tx.compute_txid());
+                        let outpoint = OutPoint::new(txid, vout as u32);
+                        counts.p2pk_created.push((outpoint, output.clone()));
+                    }
+                }
+
+                match classify_script(&output.script_pubkey, rules.loose) {
+                    ScriptType::P2TR => counts.p2tr_outputs_created += 1,
+                    ScriptType::Multisig => {
+                        totals.multisig_outputs += 1;
+                        totals.multisig_coins += output.value.to_btc();
+                    }
+                    ScriptType::P2SH => {
+                        totals.p2sh_outputs += 1;
+                        totals.p2sh_coins += output.value.to_btc();
+                    }
+                    _ => {}
+                }
+                if output.script_pubkey.is_op_return() {
+                    totals.burned_sats += output.value.to_sat();
+                    if self.details {
+                        let txid = *txid.get_or_insert_with(|| tx.compute_txid());
+                        let payload = op_return_payload(&output.script_pubkey).unwrap_or_default();
+                        counts.op_returns.push((txid, payload));
+                    }
+                }
+                if let Some((_, n @ 1..=3)) = is_bare_multisig(&output.script_pubkey) {
+                    counts.multisig_keys_exposed[n as usize - 1] += n as u64;
+                }
+                counts.sats_added += output.value.to_sat() as u128;
+            }
+
+            counts.transactions += 1;
+            counts.inputs += tx.input.len() as u64;
+            counts.outputs += tx.output.len() as u64;
+
+            // If the transaction is not coinbase, account for the spent coins
+            if tx.is_coinbase() {
+                continue;
+            }
+            counts.non_coinbase_transactions += 1;
+            if tx.input.iter().any(|txin| !txin.witness.is_empty()) {
+                counts.segwit_transactions += 1;
+            }
+            for txin in &tx.input {
+                let Some((prev_output, from_coinbase)) = prevout(&txin.previous_output)? else {
+                    continue;
+                };
+
+                match classify_script(&prev_output.script_pubkey, rules.loose) {
+                    ScriptType::P2TR => counts.p2tr_outputs_spent += 1,
+                    ScriptType::Multisig => {
+                        totals.multisig_outputs -= 1;
+                        totals.multisig_coins -= prev_output.value.to_btc();
+                    }
+                    ScriptType::P2SH => {
+                        totals.p2sh_outputs -= 1;
+                        totals.p2sh_coins -= prev_output.value.to_btc();
+                    }
+                    _ => {}
+                }
+
+                let Some(key) = rules.counted_key(&prev_output, from_coinbase) else {
+                    continue;
+                };
+                totals.p2pk_addresses -= 1;
+                totals.p2pk_coins -= prev_output.value.to_btc();
+                counts.p2pk_outputs_spent += 1;
+                counts.p2pk_sats_spent += prev_output.value.to_sat();
+                if key.len() == 33 {
+                    counts.compressed_spent += 1;
+                } else {
+                    counts.uncompressed_spent += 1;
+                }
+
+                // Outputs created before this run aren't tracked, so may be missing
+                if let Some(UniqueKeyMode::Current) = self.unique_key_mode {
+                    if let Some(unspent) = totals.key_outputs.get_mut(key) {
+                        *unspent -= 1;
+                        if *unspent == 0 {
+                            totals.key_outputs.remove(key);
+                        }
+                    }
+                }
+
+                if self.details {
+                    let txid = *txid.get_or_insert_with(|| tx.compute_txid());
+                    counts
+                        .p2pk_spent
+                        .push((txid, txin.previous_output, prev_output));
+                }
+            }
+        }
+
+        totals.total_sats_added += counts.sats_added;
+        totals.total_transactions += counts.transactions;
+        totals.total_inputs += counts.inputs;
+        totals.total_outputs += counts.outputs;
+        totals.total_segwit_transactions += counts.segwit_transactions;
+        totals.total_non_coinbase_transactions += counts.non_coinbase_transactions;
+        counts.went_negative = totals.p2pk_addresses < 0 && p2pk_addresses_before >= 0;
+
+        Ok(counts)
+    }
+}
+
+/// Percentage of `transactions` that are segwit, or 0 if there are none.
+pub fn segwit_adoption(segwit_transactions: u64, transactions: u64) -> f64 {
+    match transactions {
+        0 => 0.0,
+        n => 100.0 * segwit_transactions as f64 / n as f64,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bitcoin::{
+        absolute::LockTime, blockdata::constants::genesis_block, hashes::Hash, transaction, Amount,
+        BlockHash, CompactTarget, Network, ScriptBuf, Sequence, Transaction, TxIn, TxMerkleNode,
+        Witness,
+    };
+
+    use super::*;
+
+    /// Generator point G, compressed
+    pub(crate) const KEY_G: &str =
+        "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    /// Key of the genesis coinbase output, uncompressed
+    const KEY_GENESIS: &str = "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f";
+
+    pub(crate) fn p2pk(key: &str, sats: u64) -> TxOut {
+        let key = hex::decode(key).unwrap();
+        let mut script = vec![key.len() as u8];
+        script.extend(key);
+        script.push(0xac);
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::from_bytes(script),
+        }
+    }
+
+    pub(crate) fn output(script_hex: &str, sats: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey: ScriptBuf::from_hex(script_hex).unwrap(),
+        }
+    }
+
+    pub(crate) fn tx(previous_outputs: &[OutPoint], output: Vec<TxOut>) -> Transaction {
+        let input = previous_outputs
+            .iter()
+            .map(|&previous_output| TxIn {
+                previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            })
+            .collect();
+        Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input,
+            output,
+        }
+    }
+
+    pub(crate) fn coinbase(output: Vec<TxOut>) -> Transaction {
+        tx(&[OutPoint::null()], output)
+    }
+
+    pub(crate) fn block(txdata: Vec<Transaction>) -> Block {
+        let mut block = genesis_block(Network::Regtest);
+        block.header.prev_blockhash = BlockHash::all_zeros();
+        block.header.merkle_root = TxMerkleNode::all_zeros();
+        block.header.bits = CompactTarget::from_consensus(0);
+        block.txdata = txdata;
+        block
+    }
+
+    /// Accounts for `blocks` in order, looking prevouts up in the earlier blocks.
+    fn run(accounting: &Accounting, blocks: &[Block]) -> (Totals, Vec<BlockCounts>) {
+        let mut totals = Totals::default();
+        let mut prevouts = HashMap::new();
+        let mut counts = Vec::new();
+        for block in blocks {
+            counts.push(
+                accounting
+                    .account_block(block, &mut totals, |outpoint| {
+                        Ok(prevouts.get(outpoint).cloned())
+                    })
+                    .unwrap(),
+            );
+            for tx in &block.txdata {
+                let txid = tx.compute_txid();
+                for (vout, output) in tx.output.iter().enumerate() {
+                    let outpoint = OutPoint::new(txid, vout as u32);
+                    prevouts.insert(outpoint, (output.clone(), tx.is_coinbase()));
+                }
+            }
+        }
+        (totals, counts)
+    }
+
+    /// Floating-point BTC totals back to sats
+    fn sats(btc: f64) -> u64 {
+        (btc * 100_000_000.0).round() as u64
+    }
+
+    fn accounting(rules: P2pkRules) -> Accounting {
+        Accounting {
+            rules,
+            unique_key_mode: None,
+            track_unspendable: false,
+            details: true,
+        }
+    }
+
+    #[test]
+    fn counts_p2pk_outputs_created_and_spent() {
+        let funding = tx(&[], vec![p2pk(KEY_G, 1_000), p2pk(KEY_GENESIS, 2_000)]);
+        let spending = tx(
+            &[OutPoint::new(funding.compute_txid(), 0)],
+            vec![output("0014751e76e8199196d454941c45d1b3a323f1433bd6", 900)],
+        );
+        let blocks = [block(vec![funding]), block(vec![spending])];
+
+        let (totals, counts) = run(&accounting(P2pkRules::default()), &blocks);
+
+        assert_eq!(totals.p2pk_addresses, 1);
+        assert_eq!(sats(totals.p2pk_coins), 2_000);
+        assert_eq!(counts[0].p2pk_outputs_created, 2);
+        assert_eq!(counts[0].p2pk_sats_added, 3_000);
+        assert_eq!(counts[0].p2pk_created.len(), 2);
+        assert_eq!(counts[1].p2pk_outputs_spent, 1);
+        assert_eq!(counts[1].p2pk_sats_spent, 1_000);
+        assert_eq!(counts[1].p2pk_spent.len(), 1);
+        assert_eq!(totals.total_transactions, 2);
+        assert_eq!(totals.total_sats_added, 3_900);
+    }
+
+    #[test]
+    fn excluded_coinbase_outputs_are_skipped_when_spent_too() {
+        let reward = coinbase(vec![p2pk(KEY_G, 5_000)]);
+        let spending = tx(&[OutPoint::new(reward.compute_txid(), 0)], vec![]);
+        let blocks = [block(vec![reward]), block(vec![spending])];
+        let rules = P2pkRules {
+            exclude_coinbase: true,
+            ..Default::default()
+        };
+
+        let (totals, counts) = run(&accounting(rules), &blocks);
+
+        assert_eq!(totals.p2pk_addresses, 0);
+        assert_eq!(counts[0].p2pk_outputs_created, 0);
+        assert_eq!(counts[1].p2pk_outputs_spent, 0);
+    }
+
+    #[test]
+    fn value_band_and_burn_list_apply_to_both_sides() {
+        let funding = tx(
+            &[],
+            vec![
+                p2pk(KEY_G, 10),
+                p2pk(KEY_G, 1_000),
+                p2pk(KEY_GENESIS, 1_000),
+            ],
+        );
+        let txid = funding.compute_txid();
+        let spending = tx(&[0, 1, 2].map(|vout| OutPoint::new(txid, vout)), vec![]);
+        let blocks = [block(vec![funding]), block(vec![spending])];
+        let rules = P2pkRules {
+            min_value: Some(100),
+            burn_keys: HashSet::from([hex::decode(KEY_GENESIS).unwrap()]),
+            ..Default::default()
+        };
+
+        let (totals, counts) = run(&accounting(rules), &blocks);
+
+        assert_eq!(counts[0].p2pk_outputs_created, 1);
+        assert_eq!(counts[1].p2pk_outputs_spent, 1);
+        assert_eq!(totals.p2pk_addresses, 0);
+        assert_eq!(sats(totals.burned_p2pk_coins), 1_000);
+    }
+
+    #[test]
+    fn unique_keys_ever_and_current() {
+        let funding = tx(
+            &[],
+            vec![p2pk(KEY_G, 1), p2pk(KEY_G, 2), p2pk(KEY_GENESIS, 3)],
+        );
+        let txid = funding.compute_txid();
+        let spend_one = tx(&[OutPoint::new(txid, 0)], vec![]);
+        let spend_both = tx(&[OutPoint::new(txid, 1), OutPoint::new(txid, 2)], vec![]);
+        // A key whose outputs were all spent, paid again
+        let repay = tx(&[], vec![p2pk(KEY_GENESIS, 4)]);
+        let blocks = [
+            block(vec![funding]),
+            block(vec![spend_one]),
+            block(vec![spend_both]),
+            block(vec![repay]),
+        ];
+
+        let unique_keys = |mode| {
+            let accounting = Accounting {
+                unique_key_mode: Some(mode),
+                ..accounting(P2pkRules::default())
+            };
+            let mut keys = Vec::new();
+            for end in 1..=blocks.len() {
+                keys.push(run(&accounting, &blocks[..end]).0.key_outputs.len());
+            }
+            keys
+        };
+
+        assert_eq!(unique_keys(UniqueKeyMode::Ever), [2, 2, 2, 2]);
+        assert_eq!(unique_keys(UniqueKeyMode::Current), [2, 2, 0, 1]);
+    }
+
+    #[test]
+    fn counts_compressed_and_uncompressed_keys() {
+        let funding = tx(
+            &[],
+            vec![p2pk(KEY_G, 1), p2pk(KEY_G, 1), p2pk(KEY_GENESIS, 1)],
+        );
+        let spending = tx(&[OutPoint::new(funding.compute_txid(), 2)], vec![]);
+        let blocks = [block(vec![funding]), block(vec![spending])];
+
+        let (_, counts) = run(&accounting(P2pkRules::default()), &blocks);
+
+        assert_eq!(counts[0].compressed_created, 2);
+        assert_eq!(counts[0].uncompressed_created, 1);
+        assert_eq!(counts[1].compressed_spent, 0);
+        assert_eq!(counts[1].uncompressed_spent, 1);
+    }
+
+    #[test]
+    fn tracks_multisig_and_p2sh_outputs() {
+        let multisig = format!("5121{KEY_G}51ae");
+        let funding = tx(
+            &[],
+            vec![
+                output(&multisig, 700),
+                output("a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87", 300),
+                output("6a0568656c6c6f", 5),
+            ],
+        );
+        let spending = tx(&[OutPoint::new(funding.compute_txid(), 1)], vec![]);
+        let blocks = [block(vec![funding]), block(vec![spending])];
+
+        let (totals, counts) = run(&accounting(P2pkRules::default()), &blocks);
+
+        assert_eq!(counts[0].multisig_keys_exposed, [1, 0, 0]);
+        assert_eq!(totals.multisig_outputs, 1);
+        assert_eq!(sats(totals.multisig_coins), 700);
+        assert_eq!(totals.p2sh_outputs, 0);
+        assert_eq!(totals.burned_sats, 5);
+        assert_eq!(counts[0].op_returns[0].1, b"hello");
+    }
+
+    #[test]
+    fn unspendable_keys_are_only_checked_when_tracked() {
+        // There is no point on the curve with x = 0
+        let off_curve = format!("02{}", "00".repeat(32));
+        let funding = tx(&[], vec![p2pk(&off_curve, 400), p2pk(KEY_G, 600)]);
+        let blocks = [block(vec![funding])];
+
+        let (totals, _) = run(&accounting(P2pkRules::default()), &blocks);
+        assert_eq!(totals.unspendable_outputs, 0);
+
+        let tracked = Accounting {
+            track_unspendable: true,
+            ..accounting(P2pkRules::default())
+        };
+        let (totals, _) = run(&tracked, &blocks);
+        assert_eq!(totals.unspendable_outputs, 1);
+        assert_eq!(sats(totals.unspendable_coins), 400);
+    }
+
+    #[test]
+    fn settings_describe_the_rules() {
+        assert_eq!(
+            P2pkRules::default().settings(),
+            "loose_p2pk=false,exclude_coinbase_p2pk=false,min_value=none,max_value=none,burn_list=none"
+        );
+
+        let rules = P2pkRules {
+            exclude_coinbase: true,
+            max_value: Some(5_000),
+            burn_keys: HashSet::from([
+                hex::decode(KEY_G).unwrap(),
+                hex::decode(KEY_GENESIS).unwrap(),
+            ]),
+            ..Default::default()
+        };
+        let settings = rules.settings();
+        assert!(settings.starts_with(
+            "loose_p2pk=false,exclude_coinbase_p2pk=true,min_value=none,max_value=5000,burn_list="
+        ));
+        assert!(!settings.ends_with("burn_list=none"));
+
+        // The hash doesn't depend on the set's iteration order, but does on its keys
+        let same = P2pkRules {
+            burn_keys: HashSet::from([
+                hex::decode(KEY_GENESIS).unwrap(),
+                hex::decode(KEY_G).unwrap(),
+            ]),
+            ..rules
+        };
+        assert_eq!(same.settings(), settings);
+        let fewer = P2pkRules {
+            burn_keys: HashSet::from([hex::decode(KEY_G).unwrap()]),
+            exclude_coinbase: true,
+            max_value: Some(5_000),
+            ..Default::default()
+        };
+        assert_ne!(fewer.settings(), settings);
+    }
+
+    #[test]
+    fn key_compression_counts_add_up_to_the_p2pk_counts() {
+        // The genesis key pushed with OP_PUSHDATA1, only P2PK with LOOSE_P2PK
+        let pushdata1 = output(&format!("4c41{KEY_GENESIS}ac"), 7);
+        let funding = tx(&[], vec![p2pk(KEY_G, 1), p2pk(KEY_GENESIS, 2), pushdata1]);
+        let txid = funding.compute_txid();
+        let spending = tx(&[0, 1, 2].map(|vout| OutPoint::new(txid, vout)), vec![]);
+        let blocks = [block(vec![funding]), block(vec![spending])];
+
+        for (loose, uncompressed) in [(false, 1), (true, 2)] {
+            let rules = P2pkRules {
+                loose,
+                ..Default::default()
+            };
+            let (_, counts) = run(&accounting(rules), &blocks);
+
+            assert_eq!(counts[0].compressed_created, 1);
+            assert_eq!(counts[0].uncompressed_created, uncompressed);
+            assert_eq!(counts[1].compressed_spent, 1);
+            assert_eq!(counts[1].uncompressed_spent, uncompressed);
+            assert_eq!(
+                counts[0].compressed_created + counts[0].uncompressed_created,
+                counts[0].p2pk_outputs_created
+            );
+            assert_eq!(
+                counts[1].compressed_spent + counts[1].uncompressed_spent,
+                counts[1].p2pk_outputs_spent
+            );
+        }
+    }
+
+    #[test]
+    fn segwit_adoption_leaves_out_the_coinbase() {
+        // Segwit coinbases carry the witness reserved value
+        let mut segwit_coinbase = coinbase(vec![]);
+        segwit_coinbase.input[0].witness = Witness::from_slice(&[[0u8; 32]]);
+        let legacy = tx(&[OutPoint::new(Txid::all_zeros(), 0)], vec![]);
+        let mut segwit = tx(&[OutPoint::new(Txid::all_zeros(), 1)], vec![]);
+        segwit.input[0].witness = Witness::from_slice(&[&[0u8; 72][..], &[2u8; 33]]);
+        let blocks = [
+            block(vec![segwit_coinbase.clone()]),
+            block(vec![segwit_coinbase.clone(), legacy.clone(), segwit]),
+            block(vec![segwit_coinbase, legacy]),
+        ];
+        let (totals, counts) = run(&accounting(P2pkRules::default()), &blocks);
+
+        let adoption: Vec<f64> = counts
+            .iter()
+            .map(|counts| {
+                segwit_adoption(counts.segwit_transactions, counts.non_coinbase_transactions)
+            })
+            .collect();
+        assert_eq!(adoption, [0.0, 50.0, 0.0]);
+        assert_eq!(totals.total_segwit_transactions, 1);
+        assert_eq!(totals.total_non_coinbase_transactions, 3);
+        assert_eq!(
+            segwit_adoption(
+                totals.total_segwit_transactions,
+                totals.total_non_coinbase_transactions
+            ),
+            100.0 / 3.0
+        );
+    }
+
+    #[test]
+    fn spending_an_uncounted_output_is_flagged_once() {
+        let accounting = accounting(P2pkRules::default());
+        // The prevouts are P2PK, but were never counted as created
+        let uncounted = |_: &OutPoint| Ok(Some((p2pk(KEY_G, 1_000), false)));
+        let spend = |vout| tx(&[OutPoint::new(Txid::all_zeros(), vout)], vec![]);
+        let mut totals = Totals::default();
+
+        let first = accounting
+            .account_block(
+                &block(vec![coinbase(vec![]), spend(0)]),
+                &mut totals,
+                uncounted,
+            )
+            .unwrap();
+        assert!(first.went_negative);
+        assert_eq!(totals.p2pk_addresses, -1);
+
+        // Already negative, so not flagged again
+        let second = accounting
+            .account_block(
+                &block(vec![coinbase(vec![]), spend(1)]),
+                &mut totals,
+                uncounted,
+            )
+            .unwrap();
+        assert!(!second.went_negative);
+        assert_eq!(totals.p2pk_addresses, -2);
+
+        // Back to zero and below again
+        let funding = block(vec![coinbase(vec![p2pk(KEY_G, 1_000); 3])]);
+        accounting
+            .account_block(&funding, &mut totals, uncounted)
+            .unwrap();
+        assert_eq!(totals.p2pk_addresses, 1);
+        let third = accounting
+            .account_block(
+                &block(vec![coinbase(vec![]), spend(2), spend(3)]),
+                &mut totals,
+                uncounted,
+            )
+            .unwrap();
+        assert!(third.went_negative);
+    }
+
+    #[test]
+    fn totals_are_the_running_sum_of_the_deltas() {
+        let first = coinbase(vec![p2pk(KEY_G, 5_000_000_000)]);
+        let funding = tx(
+            &[],
+            vec![p2pk(KEY_G, 1_000), p2pk(KEY_GENESIS, 2_500), p2pk(KEY_G, 7)],
+        );
+        let outpoint = |tx: &Transaction, vout| OutPoint::new(tx.compute_txid(), vout);
+        let blocks = [
+            block(vec![first.clone()]),
+            block(vec![funding.clone()]),
+            block(vec![tx(
+                &[outpoint(&first, 0), outpoint(&funding, 1)],
+                vec![],
+            )]),
+            block(vec![tx(&[outpoint(&funding, 0)], vec![p2pk(KEY_G, 600)])]),
+            block(vec![tx(&[outpoint(&funding, 2)], vec![])]),
+        ];
+        let accounting = accounting(P2pkRules::default());
+
+        // The totals after each block, as its row would have them, against the sum of the rows so far
+        let (mut addresses, mut sats_held) = (0i64, 0i64);
+        for end in 1..=blocks.len() {
+            let (totals, counts) = run(&accounting, &blocks[..end]);
+            let counts = counts.last().unwrap();
+            addresses += counts.p2pk_outputs_created as i64 - counts.p2pk_outputs_spent as i64;
+            sats_held += counts.p2pk_sats_added as i64 - counts.p2pk_sats_spent as i64;
+            assert_eq!(totals.p2pk_addresses, addresses, "block {end}");
+            assert_eq!(sats(totals.p2pk_coins) as i64, sats_held, "block {end}");
+        }
+        assert_eq!((addresses, sats_held), (1, 600));
+    }
+}
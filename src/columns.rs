@@ -0,0 +1,227 @@
+//! Columns of the output CSV: the header and the row written for each block.
+
+use bitcoin::{Block, Network};
+
+use crate::{
+    accounting::{segwit_adoption, BlockCounts, Totals, UniqueKeyMode},
+    block_subsidy,
+    date::{block_date, DateFormat},
+    p2pk_supply_share, HEADER,
+};
+
+/// Optional columns, which follow the totals in a fixed order.
+pub struct Columns {
+    pub network: Network,
+    pub date_format: DateFormat,
+    pub output_counts: bool,
+    pub burned: bool,
+    pub multisig_key_stats: bool,
+    pub segwit_stats: bool,
+    pub unspendable: bool,
+    pub p2pk_share: bool,
+    pub unique_keys: Option<UniqueKeyMode>,
+    pub burned_p2pk: bool,
+    pub p2tr: bool,
+    pub key_compression_stats: bool,
+    pub supply: bool,
+    pub multisig: bool,
+    pub p2sh: bool,
+    pub deltas: bool,
+    pub stats: bool,
+}
+
+impl Columns {
+    /// Just the totals, on `network`, with ISO dates.
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            date_format: DateFormat::Iso,
+            output_counts: false,
+            burned: false,
+            multisig_key_stats: false,
+            segwit_stats: false,
+            unspendable: false,
+            p2pk_share: false,
+            unique_keys: None,
+            burned_p2pk: false,
+            p2tr: false,
+            key_compression_stats: false,
+            supply: false,
+            multisig: false,
+            p2sh: false,
+            deltas: false,
+            stats: false,
+        }
+    }
+
+    pub fn header(&self) -> String {
+        let mut header = HEADER.to_owned();
+        if self.output_counts {
+            header.push_str(",P2PK outputs created,P2PK outputs spent");
+        }
+        if self.burned {
+            header.push_str(",Burned sats (OP_RETURN)");
+        }
+        if self.multisig_key_stats {
+            header.push_str(",Multisig keys exposed (1-key),Multisig keys exposed (2-key),Multisig keys exposed (3-key)");
+        }
+        if self.segwit_stats {
+            header.push_str(",Block weight,Segwit adoption %");
+            header.push_str(
+                ",Total segwit transactions,Total non-coinbase transactions,Cumulative segwit adoption %",
+            );
+        }
+        if self.unspendable {
+            header.push_str(",Unspendable P2PK outputs,Unspendable P2PK coins");
+        }
+        if self.p2pk_share {
+            header.push_str(",P2PK % of supply");
+        }
+        match self.unique_keys {
+            Some(UniqueKeyMode::Ever) => header.push_str(",Unique P2PK keys (ever)"),
+            Some(UniqueKeyMode::Current) => header.push_str(",Unique P2PK keys (current)"),
+            None => {}
+        }
+        if self.burned_p2pk {
+            header.push_str(",Burned P2PK coins");
+        }
+        if self.p2tr {
+            header.push_str(",P2TR outputs created,P2TR outputs spent");
+        }
+        if self.key_compression_stats {
+            header.push_str(",Compressed P2PK outputs created,Compressed P2PK outputs spent");
+            header.push_str(",Uncompressed P2PK outputs created,Uncompressed P2PK outputs spent");
+        }
+        if self.supply {
+            header.push_str(",Sats added,Block subsidy,Total sats added");
+        }
+        if self.multisig {
+            header.push_str(",Total bare multisig outputs,Total bare multisig coins");
+        }
+        if self.p2sh {
+            header.push_str(",Total P2SH outputs,Total P2SH coins");
+        }
+        if self.deltas {
+            header.push_str(
+                ",P2PK addresses added,P2PK addresses spent,P2PK sats added,P2PK sats spent",
+            );
+        }
+        if self.stats {
+            header.push_str(",Transactions,Inputs,Outputs");
+            header.push_str(",Total transactions,Total inputs,Total outputs");
+        }
+        header
+    }
+
+    /// The block's header timestamp, as written to the `Date` column.
+    pub fn date(&self, block: &Block) -> String {
+        block_date(block.header.time)
+            .format(self.date_format.pattern())
+            .to_string()
+    }
+
+    /// Builds the row for a block from its counts and the totals including it.
+    pub fn row(&self, height: u64, block: &Block, counts: &BlockCounts, totals: &Totals) -> String {
+        let mut row = format!(
+            "{height},{},{},{}",
+            self.date(block),
+            totals.p2pk_addresses,
+            totals.p2pk_coins
+        );
+        if self.output_counts {
+            row.push_str(&format!(
+                ",{},{}",
+                counts.p2pk_outputs_created, counts.p2pk_outputs_spent
+            ));
+        }
+        if self.burned {
+            row.push_str(&format!(",{}", totals.burned_sats));
+        }
+        if self.multisig_key_stats {
+            let [one, two, three] = counts.multisig_keys_exposed;
+            row.push_str(&format!(",{one},{two},{three}"));
+        }
+        if self.segwit_stats {
+            let adoption =
+                segwit_adoption(counts.segwit_transactions, counts.non_coinbase_transactions);
+            let cumulative = segwit_adoption(
+                totals.total_segwit_transactions,
+                totals.total_non_coinbase_transactions,
+            );
+            row.push_str(&format!(
+                ",{},{adoption},{},{},{cumulative}",
+                block.weight().to_wu(),
+                totals.total_segwit_transactions,
+                totals.total_non_coinbase_transactions
+            ));
+        }
+        if self.unspendable {
+            row.push_str(&format!(
+                ",{},{}",
+                totals.unspendable_outputs, totals.unspendable_coins
+            ));
+        }
+        if self.p2pk_share {
+            let share = p2pk_supply_share(totals.p2pk_coins, height, self.network);
+            row.push_str(&format!(",{share}"));
+        }
+        if self.unique_keys.is_some() {
+            row.push_str(&format!(",{}", totals.key_outputs.len()));
+        }
+        if self.burned_p2pk {
+            row.push_str(&format!(",{}", totals.burned_p2pk_coins));
+        }
+        if self.p2tr {
+            row.push_str(&format!(
+                ",{},{}",
+                counts.p2tr_outputs_created, counts.p2tr_outputs_spent
+            ));
+        }
+        if self.key_compression_stats {
+            row.push_str(&format!(
+                ",{},{},{},{}",
+                counts.compressed_created,
+                counts.compressed_spent,
+                counts.uncompressed_created,
+                counts.uncompressed_spent
+            ));
+        }
+        if self.supply {
+            let subsidy = block_subsidy(height, self.network);
+            row.push_str(&format!(
+                ",{},{subsidy},{}",
+                counts.sats_added, totals.total_sats_added
+            ));
+        }
+        if self.multisig {
+            row.push_str(&format!(
+                ",{},{}",
+                totals.multisig_outputs, totals.multisig_coins
+            ));
+        }
+        if self.p2sh {
+            row.push_str(&format!(",{},{}", totals.p2sh_outputs, totals.p2sh_coins));
+        }
+        if self.deltas {
+            row.push_str(&format!(
+                ",{},{},{},{}",
+                counts.p2pk_outputs_created,
+                counts.p2pk_outputs_spent,
+                counts.p2pk_sats_added,
+                counts.p2pk_sats_spent
+            ));
+        }
+        if self.stats {
+            row.push_str(&format!(
+                ",{},{},{},{},{},{}",
+                counts.transactions,
+                counts.inputs,
+                counts.outputs,
+                totals.total_transactions,
+                totals.total_inputs,
+                totals.total_outputs
+            ));
+        }
+        row
+    }
+}
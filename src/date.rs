@@ -0,0 +1,57 @@
+//! Formats of the `Date` column in the output CSV.
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+
+/// How block timestamps are written to the `Date` column, always in UTC.
+#[derive(Clone, Copy, PartialEq)]
+pub enum DateFormat {
+    /// `MM/DD/YYYY HH:MM:SS`, as written by earlier versions
+    Us,
+    /// ISO 8601, `YYYY-MM-DDTHH:MM:SSZ`, which also sorts lexicographically
+    Iso,
+}
+
+impl DateFormat {
+    pub fn pattern(self) -> &'static str {
+        match self {
+            DateFormat::Us => "%m/%d/%Y %H:%M:%S",
+            DateFormat::Iso => "%Y-%m-%dT%H:%M:%SZ",
+        }
+    }
+
+    /// Returns the format `value` was written in, if it's either.
+    pub fn detect(value: &str) -> Option<Self> {
+        [DateFormat::Us, DateFormat::Iso]
+            .into_iter()
+            .find(|format| NaiveDateTime::parse_from_str(value, format.pattern()).is_ok())
+    }
+}
+
+/// Parses a `Date` value written in any of the formats, so files written with either can be read.
+pub fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    let format = DateFormat::detect(value)?;
+    NaiveDateTime::parse_from_str(value, format.pattern()).ok()
+}
+
+/// Converts a block header timestamp. Every `u32` is a valid number of seconds since the epoch,
+/// so unlike `timestamp_opt` this can't fail.
+pub fn block_date(time: u32) -> DateTime<Utc> {
+    DateTime::UNIX_EPOCH + TimeDelta::seconds(time.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_date_covers_the_whole_u32_range() {
+        let format = |time| {
+            block_date(time)
+                .format(DateFormat::Iso.pattern())
+                .to_string()
+        };
+        assert_eq!(format(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format(1231006505), "2009-01-03T18:15:05Z");
+        assert_eq!(format(u32::MAX), "2106-02-07T06:28:15Z");
+    }
+}
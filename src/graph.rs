@@ -0,0 +1,223 @@
+//! Rendering of an output CSV column as a PNG time series.
+
+use std::fs;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use plotters::prelude::*;
+
+use crate::{date::parse_date, drop_duplicate_rows};
+
+/// Opaque fills, so the held area fully covers the created area beneath it
+const SPENT: RGBColor = RGBColor(240, 150, 150);
+const HELD: RGBColor = RGBColor(110, 110, 230);
+
+/// Points of a time series
+type Series = Vec<(DateTime<Utc>, f64)>;
+
+/// What the graph shows.
+#[derive(Clone, Copy, PartialEq)]
+pub enum GraphMode {
+    /// A single column as a line
+    Line,
+    /// P2PK coins still held, with those spent stacked on top, from the per-block columns
+    Stacked,
+}
+
+/// Per-block columns the stacked graph accumulates, with the factor converting them to its unit:
+/// the `WITH_DELTAS` sats when present, otherwise the `WITH_OUTPUT_COUNTS` outputs.
+const STACKED_COLUMNS: [(&str, &str, f64, &str); 2] = [
+    ("P2PK sats added", "P2PK sats spent", 1e-8, "P2PK coins"),
+    (
+        "P2PK outputs created",
+        "P2PK outputs spent",
+        1.0,
+        "P2PK outputs",
+    ),
+];
+
+/// Plots `column` of the CSV at `input` against its `Date` column, and writes the chart to `output`.
+/// In stacked mode, `column` is unused.
+pub fn render_graph(input: &str, output: &str, column: &str, mode: GraphMode) -> Result<()> {
+    let (header, rows) = read_csv(input)?;
+    let columns: Vec<&str> = header.split(',').collect();
+    let find_column = |name: &str| columns.iter().position(|c| *c == name);
+    let date_index =
+        find_column("Date").ok_or_else(|| anyhow!("No Date column in {input}, found: {header}"))?;
+
+    if mode == GraphMode::Stacked {
+        let Some((created, spent, scale, unit)) = STACKED_COLUMNS
+            .into_iter()
+            .find(|(created, spent, ..)| find_column(created).zip(find_column(spent)).is_some())
+        else {
+            bail!(
+                "Stacked graphs need the per-block columns written with WITH_DELTAS or WITH_OUTPUT_COUNTS, but {input} has: {header}"
+            );
+        };
+        let indices = [find_column(created).unwrap(), find_column(spent).unwrap()];
+        let deltas = parse_rows(input, &rows, date_index, &indices)?
+            .into_iter()
+            .map(|(date, values)| (date, values[0] * scale, values[1] * scale))
+            .collect::<Vec<_>>();
+        let (held, created) = stacked_series(&deltas);
+        return draw_stacked(input, output, unit, &held, &created);
+    }
+
+    let value_index = find_column(column)
+        .ok_or_else(|| anyhow!("No {column} column in {input}, found: {header}"))?;
+    let points: Series = parse_rows(input, &rows, date_index, &[value_index])?
+        .into_iter()
+        .map(|(date, values)| (date, values[0]))
+        .collect();
+
+    let (Some((first, _)), Some((last, _))) = (points.first(), points.last()) else {
+        bail!("No rows to plot in {input}");
+    };
+    let max = points.iter().map(|(_, value)| *value).fold(0.0, f64::max);
+    let min = points.iter().map(|(_, value)| *value).fold(0.0, f64::min);
+
+    let root = BitMapBackend::new(output, (1280, 720)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption(column, ("sans-serif", 28))
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(80)
+        .build_cartesian_2d(*first..*last, min..max * 1.05)?;
+    chart
+        .configure_mesh()
+        .x_labels(10)
+        .x_label_formatter(&|date| date.format("%Y-%m").to_string())
+        .draw()?;
+    chart.draw_series(LineSeries::new(points.iter().copied(), &BLUE))?;
+
+    root.present()?;
+
+    Ok(())
+}
+
+/// Reads the header and rows of an output CSV, without the `#` lines. Rows repeated by earlier
+/// runs are dropped, so per-block columns aren't counted twice.
+fn read_csv(input: &str) -> Result<(String, Vec<String>)> {
+    let content = fs::read_to_string(input)?;
+    let mut rows: Vec<String> = content
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    if rows.is_empty() {
+        bail!("{input} is empty");
+    }
+    drop_duplicate_rows(&mut rows)?;
+    let header = rows.remove(0);
+    Ok((header, rows))
+}
+
+/// Parses the date and the values at `indices` out of each row.
+fn parse_rows(
+    input: &str,
+    rows: &[String],
+    date_index: usize,
+    indices: &[usize],
+) -> Result<Vec<(DateTime<Utc>, Vec<f64>)>> {
+    rows.iter()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(',').collect();
+            let field = |index: usize| {
+                fields
+                    .get(index)
+                    .ok_or_else(|| anyhow!("Row has fewer columns than the header: {line}"))
+            };
+            let date = field(date_index)?;
+            let date = parse_date(date)
+                .ok_or_else(|| anyhow!("Invalid date {date} in {input}"))?
+                .and_utc();
+            let values = indices
+                .iter()
+                .map(|&index| Ok(field(index)?.parse()?))
+                .collect::<Result<_>>()?;
+            Ok((date, values))
+        })
+        .collect()
+}
+
+/// Accumulates per-block `(date, created, spent)` into the two stacked series: what's still held,
+/// and everything created, whose gap over the held series is what's been spent.
+fn stacked_series(deltas: &[(DateTime<Utc>, f64, f64)]) -> (Series, Series) {
+    let (mut created, mut spent) = (0.0, 0.0);
+    deltas
+        .iter()
+        .map(|&(date, block_created, block_spent)| {
+            created += block_created;
+            spent += block_spent;
+            ((date, created - spent), (date, created))
+        })
+        .unzip()
+}
+
+/// Draws the spent series stacked on the held one.
+fn draw_stacked(
+    input: &str,
+    output: &str,
+    unit: &str,
+    held: &[(DateTime<Utc>, f64)],
+    created: &[(DateTime<Utc>, f64)],
+) -> Result<()> {
+    let (Some((first, _)), Some((last, _))) = (created.first(), created.last()) else {
+        bail!("No rows to plot in {input}");
+    };
+    let max = created.iter().map(|(_, value)| *value).fold(0.0, f64::max);
+
+    let root = BitMapBackend::new(output, (1280, 720)).into_drawing_area();
+    root.fill(&WHITE)?;
+
+    let mut chart = ChartBuilder::on(&root)
+        .caption(format!("{unit}: held and spent"), ("sans-serif", 28))
+        .margin(20)
+        .x_label_area_size(40)
+        .y_label_area_size(80)
+        .build_cartesian_2d(*first..*last, 0.0..max * 1.05)?;
+    chart
+        .configure_mesh()
+        .x_labels(10)
+        .x_label_formatter(&|date| date.format("%Y-%m").to_string())
+        .draw()?;
+    chart
+        .draw_series(AreaSeries::new(created.iter().copied(), 0.0, SPENT))?
+        .label("Spent")
+        .legend(|(x, y)| Rectangle::new([(x, y - 5), (x + 10, y + 5)], SPENT.filled()));
+    chart
+        .draw_series(AreaSeries::new(held.iter().copied(), 0.0, HELD))?
+        .label("Held")
+        .legend(|(x, y)| Rectangle::new([(x, y - 5), (x + 10, y + 5)], HELD.filled()));
+    chart
+        .configure_series_labels()
+        .background_style(WHITE)
+        .border_style(BLACK)
+        .draw()?;
+
+    root.present()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacked_series_accumulate_held_under_created() {
+        let day = |d: i64| DateTime::from_timestamp(1231006505 + d * 86400, 0).unwrap();
+        let deltas = [
+            (day(0), 50.0, 0.0),
+            (day(1), 50.0, 10.0),
+            (day(2), 0.0, 30.0),
+        ];
+
+        let (held, created) = stacked_series(&deltas);
+
+        assert_eq!(held, [(day(0), 50.0), (day(1), 90.0), (day(2), 60.0)]);
+        assert_eq!(created, [(day(0), 50.0), (day(1), 100.0), (day(2), 100.0)]);
+    }
+}
@@ -1,78 +1,257 @@
 use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
     env,
-    fs::{File, OpenOptions},
-    io::{Read, Seek, Write},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, ErrorKind, IsTerminal, Read, Seek, SeekFrom, Write},
+    net::TcpListener,
+    path::Path,
+    str::FromStr,
+    time::Instant,
 };
 
-use anyhow::Result;
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    net::{UnixListener, UnixStream},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bitcoin::{consensus, Amount, Block, BlockHash, Network, OutPoint, Transaction, TxOut};
 use bitcoincore_rpc::{
     json::{GetChainTipsResultStatus, GetChainTipsResultTip},
     Auth, Client, RpcApi,
 };
-use chrono::{TimeZone, Utc};
-use indicatif::ProgressBar;
+use chrono::{NaiveDate, NaiveTime};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde_json::json;
 
-const HEADER: &str = "Height,Date,Total P2PK addresses,Total P2PK coins";
+mod accounting;
+mod columns;
+mod date;
+mod graph;
+mod progress;
+mod script;
 
-fn main() -> Result<()> {
-    let mut out: Vec<String> = vec![];
+use accounting::{Accounting, BlockCounts, P2pkRules, Totals, UniqueKeyMode};
+use columns::Columns;
+use date::{parse_date, DateFormat};
+use graph::GraphMode;
+use progress::{Progress, ProgressMode};
 
-    // Open the file if it exists, otherwise create it
-    let mut file = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(false)
-        .open("out.csv")?;
+use script::{encode_address, is_p2pk, p2pk_pubkey_bytes};
 
-    // Read the file content into a string
-    let mut content = String::new();
-    file.read_to_string(&mut content)?;
+/// Extra format the output rows are written in, alongside out.csv.
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Csv,
+    Jsonl,
+    Sqlite,
+}
 
-    // Check if the file is empty or doesn't start with the header
-    if content.is_empty() || !content.starts_with(HEADER) {
-        // If empty or no header, add the header to the beginning of out
-        out.push(HEADER.to_owned());
+const HEADER: &str = "Height,Date,Total P2PK addresses,Total P2PK coins";
+
+/// Blocks timestamped within `[since, until)`, as Unix timestamps, get a row.
+#[derive(Clone, Copy, Default)]
+struct Window {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl Window {
+    /// Whether a block timestamped `time` is past the window, and so is every block after it.
+    fn ended(self, time: u32) -> bool {
+        self.until.is_some_and(|until| time as i64 >= until)
     }
 
-    // Split the content into lines and collect into the out vector
-    out.extend(content.lines().map(|line| line.to_string()));
+    fn contains(self, time: u32) -> bool {
+        self.since.is_none_or(|since| time as i64 >= since) && !self.ended(time)
+    }
+}
 
-    // Get the last line of the CSV file and parse the height from it
-    let resume_height = if let Some(last_line) = out.last() {
-        let fields: Vec<&str> = last_line.split(',').collect();
-        if let Some(height_str) = fields.first() {
-            height_str.parse::<u64>().unwrap_or(1)
-        } else {
-            1
-        }
-    } else {
-        1
+/// Accounts for the block at `height` and builds its row. Blocks outside the window still count
+/// towards the totals, so the first row written carries everything before it, but get no row.
+fn sync_block(
+    accounting: &Accounting,
+    columns: &Columns,
+    window: Window,
+    height: u64,
+    block: &Block,
+    totals: &mut Totals,
+    prevout: impl FnMut(&OutPoint) -> Result<Option<(TxOut, bool)>>,
+) -> Result<(BlockCounts, Option<String>)> {
+    let counts = accounting.account_block(block, totals, prevout)?;
+    let row = window
+        .contains(block.header.time)
+        .then(|| columns.row(height, block, &counts, totals));
+    Ok((counts, row))
+}
+
+fn main() -> Result<()> {
+    // Where the rows are written. `-` streams each row to stdout as it's produced instead of
+    // keeping them in memory, so there is nothing to resume from and the sync starts at height 1.
+    let output = env::var("OUTPUT").unwrap_or_else(|_| "out.csv".to_owned());
+
+    // Extra format the rows are also written in
+    let format = match env::var("FORMAT").as_deref() {
+        Err(_) | Ok("csv") => OutputFormat::Csv,
+        Ok("jsonl") => OutputFormat::Jsonl,
+        Ok("sqlite") => OutputFormat::Sqlite,
+        Ok(other) => bail!("FORMAT must be csv, jsonl or sqlite, not {other}"),
     };
 
-    // If the file only contains the header, set the resume height to 1
-    let resume_height = if resume_height == 0 { 1 } else { resume_height };
+    // Look up the totals at a single height from an existing output instead of syncing. With
+    // FORMAT=sqlite, the database is read rather than the CSV.
+    if let Ok(height) = env::var("QUERY_HEIGHT") {
+        let height = height.parse()?;
+        if output == "-" {
+            bail!("QUERY_HEIGHT needs an output file to read, not OUTPUT=-");
+        }
+        let (total, source) = match format {
+            OutputFormat::Sqlite => {
+                let path = Path::new(&output).with_extension("sqlite");
+                let db = Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+                (
+                    p2pk_total_at_height_in_db(&db, height)?,
+                    path.display().to_string(),
+                )
+            }
+            _ => (p2pk_total_at_height(&output, height)?, output),
+        };
+        match total {
+            Some((addresses, coins)) => {
+                println!("Height {height}: {addresses} P2PK addresses, {coins} P2PK coins")
+            }
+            None => bail!("Height {height} not found in {source}"),
+        }
+        return Ok(());
+    }
 
-    // Get the last line of the CSV file and parse the P2PK addresses and coins from it
-    let mut p2pk_addresses: i32 = if let Some(last_line) = out.last() {
-        let fields: Vec<&str> = last_line.split(',').collect();
-        if fields.len() >= 3 {
-            fields[2].parse().unwrap_or(0)
-        } else {
-            0
+    // Render a column of an existing output CSV as a PNG instead of syncing
+    if let Ok(graph) = env::var("GRAPH") {
+        let input = env::var("GRAPH_INPUT").unwrap_or_else(|_| output.clone());
+        if input == "-" {
+            bail!("GRAPH needs a CSV to read, not -. Set GRAPH_INPUT or OUTPUT to a file");
         }
-    } else {
-        0
+        let column = env::var("GRAPH_COLUMN").unwrap_or_else(|_| "Total P2PK coins".to_owned());
+        let mode = match env::var("GRAPH_MODE").as_deref() {
+            Err(_) | Ok("line") => GraphMode::Line,
+            Ok("stacked") => GraphMode::Stacked,
+            Ok(other) => bail!("GRAPH_MODE must be line or stacked, not {other}"),
+        };
+        return graph::render_graph(&input, &graph, &column, mode);
+    }
+
+    // Chain being scanned, which sets the BIP34 activation height and the halving interval
+    let network = match env::var("NETWORK").as_deref() {
+        Err(_) | Ok("mainnet") => Network::Bitcoin,
+        Ok(network) => network.parse()?,
     };
-    let mut p2pk_coins: f64 = if let Some(last_line) = out.last() {
-        let fields: Vec<&str> = last_line.split(',').collect();
-        if fields.len() >= 4 {
-            fields[3].parse().unwrap_or(0.0)
-        } else {
-            0.0
+
+    // Optional settings
+    let loose_p2pk = env::var("LOOSE_P2PK").is_ok();
+    let exclude_coinbase_p2pk = env::var("EXCLUDE_COINBASE_P2PK").is_ok();
+    let check_max_money = env::var("CHECK_MAX_MONEY").is_ok();
+    let normalize_output = env::var("NORMALIZE_OUTPUT").is_ok();
+    let verify_witness_commitment = env::var("VERIFY_WITNESS_COMMITMENT").is_ok();
+    let verify_merkle = env::var("VERIFY_MERKLE").is_ok();
+    let with_output_counts = env::var("WITH_OUTPUT_COUNTS").is_ok();
+    let track_burned = env::var("TRACK_BURNED").is_ok();
+    let multisig_key_stats = env::var("MULTISIG_KEY_STATS").is_ok();
+    let segwit_stats = env::var("SEGWIT_STATS").is_ok();
+    let check_bip34_height = env::var("CHECK_BIP34_HEIGHT").is_ok();
+    let track_unspendable = env::var("TRACK_UNSPENDABLE").is_ok();
+    let p2pk_share = env::var("P2PK_SHARE").is_ok();
+    let unique_key_mode = match env::var("UNIQUE_KEY_MODE").as_deref() {
+        Err(_) => None,
+        Ok("ever") => Some(UniqueKeyMode::Ever),
+        Ok("current") => Some(UniqueKeyMode::Current),
+        Ok(other) => bail!("UNIQUE_KEY_MODE must be ever or current, not {other}"),
+    };
+    let burn_list = env::var("BURN_LIST").ok();
+    let emit_socket = env::var("EMIT_SOCKET").ok();
+    let dump_pubkeys = env::var("DUMP_PUBKEYS").ok();
+    let dump_opreturn = env::var("DUMP_OPRETURN").ok();
+    let trace_spends = env::var("TRACE_SPENDS").ok();
+    let track_p2tr = env::var("TRACK_P2TR").is_ok();
+    let key_compression_stats = env::var("KEY_COMPRESSION_STATS").is_ok();
+    let track_supply = env::var("TRACK_SUPPLY").is_ok();
+    let track_multisig = env::var("TRACK_MULTISIG").is_ok();
+    let quiet = env::var("QUIET").is_ok();
+    let track_p2sh = env::var("TRACK_P2SH").is_ok();
+    let with_deltas = env::var("WITH_DELTAS").is_ok();
+    let dry_run = env::var("DRY_RUN").is_ok();
+    let stats = env::var("STATS").is_ok();
+    let stop_on_height_mismatch = env::var("STOP_ON_HEIGHT_MISMATCH").is_ok();
+    // The bar only works on a terminal, so logs get plain lines by default
+    let progress_mode = match env::var("PROGRESS").as_deref() {
+        Ok("bar") => ProgressMode::Bar,
+        Ok("plain") => ProgressMode::Plain,
+        Ok("none") => ProgressMode::None,
+        Ok(other) => bail!("PROGRESS must be bar, plain or none, not {other}"),
+        Err(_) if io::stderr().is_terminal() => ProgressMode::Bar,
+        Err(_) => ProgressMode::Plain,
+    };
+    let min_value = env::var("MIN_VALUE").ok().map(|v| v.parse()).transpose()?;
+    let max_value = env::var("MAX_VALUE").ok().map(|v| v.parse()).transpose()?;
+    let max_height: Option<u64> = env::var("MAX_HEIGHT").ok().map(|v| v.parse()).transpose()?;
+
+    // Start of a YYYY-MM-DD date in UTC, `days` later, as a Unix timestamp
+    let date_bound = |var: &str, days: i64| -> Result<Option<i64>> {
+        let Ok(value) = env::var(var) else {
+            return Ok(None);
+        };
+        let date = NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+            .map_err(|e| anyhow!("{var} must be a YYYY-MM-DD date, not {value}: {e}"))?;
+        Ok(Some(
+            date.and_time(NaiveTime::MIN).and_utc().timestamp() + days * 86400,
+        ))
+    };
+    // Rows are only written for blocks timestamped from SINCE through the end of UNTIL
+    let window = Window {
+        since: date_bound("SINCE", 0)?,
+        until: date_bound("UNTIL", 1)?,
+    };
+    // Logs are appended as blocks are synced, but a run interrupted before SINCE leaves no row to
+    // resume from, so the next run would log those blocks again
+    if window.since.is_some()
+        && (dump_pubkeys.is_some() || dump_opreturn.is_some() || trace_spends.is_some())
+    {
+        bail!("SINCE can't be combined with DUMP_PUBKEYS, DUMP_OPRETURN or TRACE_SPENDS");
+    }
+    if let (Some(min), Some(max)) = (min_value, max_value) {
+        if min > max {
+            bail!("MIN_VALUE ({min}) can't be greater than MAX_VALUE ({max})");
         }
-    } else {
-        0.0
+    }
+
+    // Scan a file of raw transactions instead of syncing blocks
+    if let Ok(path) = env::var("SCAN_TXS") {
+        let hex_lines = match env::var("SCAN_TXS_FORMAT").as_deref() {
+            Ok("hex") | Err(_) => true,
+            Ok("binary") => false,
+            Ok(other) => bail!("SCAN_TXS_FORMAT must be hex or binary, not {other}"),
+        };
+        return scan_txs(&path, hex_lines, loose_p2pk);
+    }
+
+    // Public keys whose P2PK coins are known to be intentionally destroyed
+    let burn_keys = match &burn_list {
+        Some(path) => load_burn_list(path)?,
+        None => HashSet::new(),
+    };
+
+    // Only P2PK outputs within the value band are accounted for, both when created and when spent
+    let accounting = Accounting {
+        rules: P2pkRules {
+            loose: loose_p2pk,
+            exclude_coinbase: exclude_coinbase_p2pk,
+            min_value,
+            max_value,
+            burn_keys,
+        },
+        unique_key_mode,
+        track_unspendable,
+        details: dump_pubkeys.is_some() || dump_opreturn.is_some() || trace_spends.is_some(),
     };
 
     // RPC connection
@@ -89,6 +268,153 @@ fn main() -> Result<()> {
     };
     let rpc = Client::new(&url, auth)?;
 
+    // Make sure the node is on the chain we were asked to scan
+    let chain = rpc.get_blockchain_info()?.chain;
+    if chain != network {
+        bail!("The node is on {chain}, but NETWORK is {network}");
+    }
+
+    // Report the P2PK outputs created and spent in a height range instead of syncing
+    if let Ok(range) = env::var("DIFF_HEIGHTS") {
+        let (from, to) = range
+            .split_once(',')
+            .and_then(|(from, to)| Some((from.trim().parse().ok()?, to.trim().parse().ok()?)))
+            .filter(|(from, to)| from <= to)
+            .ok_or_else(|| anyhow!("DIFF_HEIGHTS must be two ascending heights, e.g. 1000,2000"))?;
+
+        let accounting = Accounting {
+            details: true,
+            ..accounting
+        };
+        return print_p2pk_diff(&rpc, &accounting, from, to);
+    }
+
+    // Optional columns follow the totals, in a fixed order
+    let mut columns = Columns {
+        output_counts: with_output_counts,
+        burned: track_burned,
+        multisig_key_stats,
+        segwit_stats,
+        unspendable: track_unspendable,
+        p2pk_share,
+        unique_keys: unique_key_mode,
+        burned_p2pk: burn_list.is_some(),
+        p2tr: track_p2tr,
+        key_compression_stats,
+        supply: track_supply,
+        multisig: track_multisig,
+        p2sh: track_p2sh,
+        deltas: with_deltas,
+        stats,
+        ..Columns::new(network)
+    };
+    let header = columns.header();
+
+    let mut stdout = (output == "-").then(|| BufWriter::new(io::stdout()));
+    if stdout.is_some() && (format != OutputFormat::Csv || normalize_output) {
+        bail!("OUTPUT=- can't be combined with FORMAT or NORMALIZE_OUTPUT");
+    }
+
+    let mut out: Vec<String> = vec![];
+
+    // Read the rows written by earlier runs, if any. Nothing is created until the sync starts, so a
+    // dry run leaves no trace.
+    let content = match stdout {
+        Some(_) => String::new(),
+        None => match fs::read_to_string(&output) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        },
+    };
+
+    // Rows are written under the columns the file was started with, so a run enabling a different
+    // set would mislabel them. Files without a header predate the optional columns.
+    let existing_header = match content.lines().next() {
+        None => None,
+        Some(line) if line.starts_with(HEADER) => Some(line),
+        Some(_) => Some(HEADER),
+    };
+    if let Some(existing_header) = existing_header.filter(|existing| *existing != header) {
+        bail!(
+            "{output} has the columns {existing_header}, but the enabled options give {header}. \
+             Run with the options it was written with, or set OUTPUT to a new file"
+        );
+    }
+
+    // The totals only mean the same thing if every row was counted under the same P2PK rules.
+    // Files without a settings line were written with the defaults.
+    let settings = accounting.rules.settings();
+    if !content.is_empty() {
+        let existing_settings = content
+            .lines()
+            .find_map(|line| line.strip_prefix("# settings="))
+            .map_or_else(|| P2pkRules::default().settings(), str::to_owned);
+        if existing_settings != settings {
+            bail!(
+                "{output} was written with {existing_settings}, but this run has {settings}. \
+                 Run with the settings it was written with, or set OUTPUT to a new file"
+            );
+        }
+    }
+    let settings = format!("# settings={settings}");
+
+    // Check if the file is empty or doesn't start with the header
+    if content.is_empty() || !content.starts_with(HEADER) {
+        // If empty or no header, add the header to the beginning of out
+        out.push(header.clone());
+    }
+
+    // Split the content into lines and collect into the out vector, dropping any trailing tip marker
+    out.extend(
+        content
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.to_string()),
+    );
+
+    // Drop rows repeated by earlier runs that resumed at the last height instead of after it
+    drop_duplicate_rows(&mut out)?;
+
+    // Pair the last row with the columns it was written with, to resume optional cumulative columns
+    let last_row: Vec<(String, String)> = out[0]
+        .split(',')
+        .zip(out.last().into_iter().flat_map(|line| line.split(',')))
+        .map(|(column, value)| (column.to_owned(), value.to_owned()))
+        .collect();
+
+    // Keep writing dates the way the existing rows have them unless a format is asked for
+    let date_format = match env::var("DATE_FORMAT").as_deref() {
+        Ok("us") => DateFormat::Us,
+        Ok("iso") => DateFormat::Iso,
+        Ok(other) => bail!("DATE_FORMAT must be us or iso, not {other}"),
+        Err(_) => resume_value::<String>(&last_row, "Date")
+            .and_then(|date| DateFormat::detect(&date))
+            .unwrap_or(DateFormat::Iso),
+    };
+    columns.date_format = date_format;
+
+    // Get the last line of the CSV file and parse the height from it
+    let last_height = if let Some(last_line) = out.last() {
+        let fields: Vec<&str> = last_line.split(',').collect();
+        if let Some(height_str) = fields.first() {
+            height_str.parse::<u64>().unwrap_or(0)
+        } else {
+            0
+        }
+    } else {
+        0
+    };
+
+    // Resume after the last height already written, since its block is included in the totals.
+    // If the file only contains the header, this starts at 1.
+    let resume_height = last_height + 1;
+
+    // Keys are only tracked in memory, so a resumed run would count just the keys it sees itself
+    if unique_key_mode.is_some() && resume_height > 1 {
+        bail!("UNIQUE_KEY_MODE needs a sync from height 1, but {output} resumes at {resume_height}. Set OUTPUT to a new file");
+    }
+
     // Get chain height from chain tip
     let result = rpc.get_chain_tips()?;
     let tip_height = result
@@ -99,57 +425,258 @@ fn main() -> Result<()> {
         .unwrap()
         .height;
 
+    // Stop after MAX_HEIGHT when it's below the tip
+    let tip_height = match max_height {
+        Some(max) => tip_height.min(max + 1),
+        None => tip_height,
+    };
+
+    // Report what would be synced, having checked the node and read the output file, and stop there
+    if dry_run {
+        let format = match format {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Jsonl => "csv, jsonl",
+            OutputFormat::Sqlite => "csv, sqlite",
+        };
+        println!("Network: {network}");
+        println!("Output: {output} ({format})");
+        println!("Columns: {}", out[0]);
+        println!(
+            "Blocks: {resume_height} to {}",
+            tip_height.saturating_sub(1)
+        );
+        println!("Total blocks: {}", tip_height.saturating_sub(resume_height));
+        return Ok(());
+    }
+
+    // Open the file if it exists, otherwise create it
+    let mut file = match stdout {
+        Some(_) => None,
+        None => Some(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&output)?,
+        ),
+    };
+    if let Some(stdout) = &mut stdout {
+        writeln!(stdout, "{}", out[0])?;
+    }
+
+    // Pick up the cumulative totals from the last row
+    let mut totals = Totals {
+        p2pk_addresses: resume_value(&last_row, "Total P2PK addresses").unwrap_or(0),
+        p2pk_coins: resume_value(&last_row, "Total P2PK coins").unwrap_or(0.0),
+        burned_sats: resume_value(&last_row, "Burned sats (OP_RETURN)").unwrap_or(0),
+        unspendable_outputs: resume_value(&last_row, "Unspendable P2PK outputs").unwrap_or(0),
+        unspendable_coins: resume_value(&last_row, "Unspendable P2PK coins").unwrap_or(0.0),
+        burned_p2pk_coins: resume_value(&last_row, "Burned P2PK coins").unwrap_or(0.0),
+        total_sats_added: resume_value(&last_row, "Total sats added").unwrap_or(0),
+        multisig_outputs: resume_value(&last_row, "Total bare multisig outputs").unwrap_or(0),
+        multisig_coins: resume_value(&last_row, "Total bare multisig coins").unwrap_or(0.0),
+        p2sh_outputs: resume_value(&last_row, "Total P2SH outputs").unwrap_or(0),
+        p2sh_coins: resume_value(&last_row, "Total P2SH coins").unwrap_or(0.0),
+        total_transactions: resume_value(&last_row, "Total transactions").unwrap_or(0),
+        total_inputs: resume_value(&last_row, "Total inputs").unwrap_or(0),
+        total_outputs: resume_value(&last_row, "Total outputs").unwrap_or(0),
+        total_segwit_transactions: resume_value(&last_row, "Total segwit transactions")
+            .unwrap_or(0),
+        total_non_coinbase_transactions: resume_value(&last_row, "Total non-coinbase transactions")
+            .unwrap_or(0),
+        key_outputs: HashMap::new(),
+    };
+
+    // Public keys written to the pubkey dump, including by earlier runs
+    let mut pubkey_dump = dump_pubkeys.as_deref().map(open_pubkey_dump).transpose()?;
+
+    // Null data outputs, appended as they're found
+    let mut opreturn_dump = dump_opreturn
+        .as_deref()
+        .map(|path| open_csv_log(path, "Height,Txid,Payload"))
+        .transpose()?;
+
+    // Every P2PK spend counted against the totals, for auditing them
+    let mut spend_trace = trace_spends
+        .as_deref()
+        .map(|path| open_csv_log(path, "Height,Spending txid,Prev txid,Vout,Sats"))
+        .transpose()?;
+
+    // Stream each block's record to live consumers as it's finalized
+    let mut emitter = emit_socket.as_deref().map(Emitter::bind).transpose()?;
+
+    // Database mirroring the rows, and the highest height already written to it this run
+    let db = match format {
+        OutputFormat::Sqlite => {
+            let db = Connection::open(Path::new(&output).with_extension("sqlite"))?;
+            create_blocks_table(&db)?;
+            Some(db)
+        }
+        _ => None,
+    };
+    let mut db_height = 0;
+
     // Progress bar
-    let pb = ProgressBar::new(tip_height);
+    let mut pb = Progress::new(tip_height, progress_mode);
     pb.inc(resume_height - 1);
     pb.println(format!(
         "Syncing from blocks {resume_height} to {tip_height}"
     ));
 
+    // Last block processed, reported as the end of the dataset. Until a block is processed, the
+    // dataset still ends where the previous run left it.
+    let mut last_block = None;
+    let mut tip_marker = content
+        .lines()
+        .find(|line| line.starts_with("# tip="))
+        .map(str::to_owned);
+
+    // The block the next one must build on. A file whose tip the node no longer has at that height
+    // was synced on a fork that has since been reorganized away.
+    let mut last_good = None;
+    if let Some((hash, height)) = tip_marker.as_deref().and_then(parse_tip_marker) {
+        let node_hash = rpc.get_block_hash(height)?;
+        if node_hash != hash {
+            let mismatch = format!(
+                "{output} ends at block {hash} at height {height}, but the node has {node_hash} there"
+            );
+            if stop_on_height_mismatch {
+                bail!("{mismatch}. Rows from the stale fork need to be synced again");
+            }
+            pb.println(format!("WARNING: {mismatch}"));
+        } else if height + 1 == resume_height {
+            last_good = Some((height, hash));
+        }
+    }
+    let mut mismatch = None;
+
+    // Reported in the summary once the sync is done
+    let started = Instant::now();
+    let mut blocks_processed: u64 = 0;
+
     // For each block, account for P2PK coins
     for height in resume_height..tip_height {
         let hash = rpc.get_block_hash(height)?;
         let block = rpc.get_block(&hash)?;
 
-        // Account for the new P2PK coins
-        for tx in block.txdata.iter() {
-            for outpoint in &tx.output {
-                if outpoint.script_pubkey.is_p2pk() {
-                    p2pk_addresses += 1;
-                    p2pk_coins += outpoint.value.to_btc();
-                }
+        // Each block must build on the last one synced, or a reorg happened during the sync
+        if let Some(chain_break) = chain_break(last_good, height, &block) {
+            if stop_on_height_mismatch {
+                mismatch = Some(chain_break);
+                break;
             }
+            pb.println(format!("WARNING: {chain_break}"));
+        }
+        last_good = Some((height, hash));
+
+        // Nothing past UNTIL gets a row, so stop here and leave the rest to a later run
+        if window.ended(block.header.time) {
+            break;
+        }
+        blocks_processed += 1;
 
-            // If the transaction is not coinbase, account for the spent coins
-            if !tx.is_coinbase() {
-                for txin in &tx.input {
-                    let txid = txin.previous_output.txid;
-                    let vout = txin.previous_output.vout;
-                    let prev_tx = rpc.get_raw_transaction(&txid, None)?;
+        // Recompute the merkle root from the transactions to catch corrupt or mismatched data
+        if verify_merkle && !block.check_merkle_root() {
+            pb.println(format!(
+                "WARNING: merkle root mismatch in block {hash} at height {height}"
+            ));
+        }
 
-                    // Check if the specific output being spent was P2PK
-                    if let Some(prev_output) = prev_tx.output.get(vout as usize) {
-                        if prev_output.script_pubkey.is_p2pk() {
-                            p2pk_addresses -= 1;
-                            p2pk_coins -= prev_output.value.to_btc();
-                        }
-                    }
+        // Blocks without segwit transactions have no commitment and pass trivially. Otherwise the
+        // coinbase witness must carry the 32-byte reserved value used to compute the commitment.
+        if verify_witness_commitment && !block.check_witness_commitment() {
+            pb.println(format!(
+                "WARNING: witness commitment mismatch in block {hash} at height {height}"
+            ));
+        }
+
+        if check_bip34_height {
+            if let Some(mismatch) = bip34_mismatch(&block, height, network) {
+                pb.println(format!("WARNING: {mismatch}"));
+            }
+        }
+
+        let (counts, row) = sync_block(
+            &accounting,
+            &columns,
+            window,
+            height,
+            &block,
+            &mut totals,
+            |outpoint| rpc_prevout(&rpc, outpoint),
+        )?;
+        let p2pk_addresses = totals.p2pk_addresses;
+        let p2pk_coins = totals.p2pk_coins;
+
+        if let Some((dumped, writer)) = &mut pubkey_dump {
+            for (_, output) in &counts.p2pk_created {
+                let Some(pubkey) = p2pk_pubkey_bytes(&output.script_pubkey, loose_p2pk) else {
+                    continue;
+                };
+                if dumped.insert(pubkey.to_vec()) {
+                    let address = encode_address(&output.script_pubkey, loose_p2pk, network)
+                        .unwrap_or_default();
+                    writeln!(writer, "{},{height},{address}", hex::encode(pubkey))?;
                 }
             }
         }
+        if let Some(writer) = &mut opreturn_dump {
+            for (txid, payload) in &counts.op_returns {
+                writeln!(writer, "{height},{txid},{}", hex::encode(payload))?;
+            }
+        }
+        if let Some(writer) = &mut spend_trace {
+            for (spending_txid, outpoint, prev_output) in &counts.p2pk_spent {
+                writeln!(
+                    writer,
+                    "{height},{spending_txid},{},{},{}",
+                    outpoint.txid,
+                    outpoint.vout,
+                    prev_output.value.to_sat()
+                )?;
+            }
+        }
+
+        if counts.went_negative {
+            pb.println(format!(
+                "WARNING: P2PK addresses went negative ({p2pk_addresses}) at height {height}"
+            ));
+        }
+
+        // P2PK coins can never exceed what has been mined so far; if they do, something was miscounted
+        if check_max_money {
+            check_mined_supply(p2pk_coins, height, network)?;
+        }
 
-        // Format block header timestamp
-        let datetime = Utc
-            .timestamp_opt(block.header.time as i64, 0)
-            .single()
-            .expect("Invalid timestamp");
+        let formatted_date = columns.date(&block);
 
-        let formatted_date = datetime.format("%m/%d/%Y %H:%M:%S").to_string();
+        // Blocks before SINCE count towards the totals and checkpoints, they just don't get a row
+        if let Some(row) = row {
+            match &mut stdout {
+                Some(stdout) => writeln!(stdout, "{row}")?,
+                None => out.push(row),
+            }
 
-        // Append the new line to the CSV file
-        out.push(format!(
-            "{height},{formatted_date},{p2pk_addresses},{p2pk_coins}",
+            if let Some(emitter) = &mut emitter {
+                emitter.accept()?;
+                emitter.send(
+                    &json!({
+                        "height": height,
+                        "hash": hash.to_string(),
+                        "date": formatted_date,
+                        "p2pk_addresses": p2pk_addresses,
+                        "p2pk_coins": p2pk_coins,
+                        "p2pk_outputs_created": counts.p2pk_outputs_created,
+                        "p2pk_outputs_spent": counts.p2pk_outputs_spent,
+                    })
+                    .to_string(),
+                );
+            }
+        }
+        tip_marker = Some(format!(
+            "# tip={hash},height={height},date={formatted_date}"
         ));
+        last_block = Some((height, hash));
 
         // Calculate ETA
         let eta_duration = pb.eta();
@@ -160,25 +687,1252 @@ fn main() -> Result<()> {
         let seconds = eta_seconds % 60;
         let eta = format!("{:02}:{:02}:{:02}:{:02}", days, hours, minutes, seconds);
 
-        pb.println(format!("Block: {height} - ETA: {eta}"));
+        if !quiet {
+            pb.println(format!("Block: {height} - ETA: {eta}"));
+        }
 
         // Write the new content to the file for every 1000 blocks
+        if height % 1000 == 0 && stdout.is_none() {
+            if normalize_output {
+                normalize_rows(&mut out);
+            }
+            let mut file = BufWriter::new(File::create(&output)?);
+            for line in &out {
+                writeln!(file, "{line}")?;
+            }
+            writeln!(file, "{settings}")?;
+            if let Some(marker) = &tip_marker {
+                writeln!(file, "{marker}")?;
+            }
+            file.flush()?;
+            match &db {
+                Some(db) => db_height = write_sqlite(db, &out, db_height)?,
+                None if format == OutputFormat::Jsonl => {
+                    write_jsonl(&Path::new(&output).with_extension("jsonl"), &out)?
+                }
+                None => {}
+            }
+            if !quiet {
+                pb.println("FILE SUCCESSFULLY SAVED TO DISK");
+            }
+        }
+
+        // Logs only take the rows of blocks the output has saved, so a resumed run doesn't repeat
+        // them. Streamed output isn't resumed, but is checkpointed alike to bound memory use.
         if height % 1000 == 0 {
-            let content = out.join("\n");
-            let mut file = File::create("out.csv")?;
-            file.write_all(content.as_bytes())?;
-            pb.println("FILE SUCCESSFULLY SAVED TO DISK");
+            if let Some((_, log)) = &mut pubkey_dump {
+                log.flush()?;
+            }
+            for log in [&mut opreturn_dump, &mut spend_trace].into_iter().flatten() {
+                log.flush()?;
+            }
         }
 
         pb.inc(1);
     }
 
+    if normalize_output {
+        normalize_rows(&mut out);
+    }
+
     // When writing back to the file, ensure we start from the beginning
-    file.seek(std::io::SeekFrom::Start(0))?;
-    file.set_len(0)?; // Truncate the file
-    for line in &out {
-        writeln!(file, "{}", line)?;
+    if let Some(file) = &mut file {
+        file.seek(SeekFrom::Start(0))?;
+        file.set_len(0)?; // Truncate the file
+        for line in &out {
+            writeln!(file, "{}", line)?;
+        }
+        writeln!(file, "{settings}")?;
+    }
+
+    match &db {
+        Some(db) => {
+            write_sqlite(db, &out, db_height)?;
+        }
+        None if format == OutputFormat::Jsonl => {
+            write_jsonl(&Path::new(&output).with_extension("jsonl"), &out)?
+        }
+        None => {}
+    }
+
+    // Mark where the dataset ends so it can be checked against an explorer
+    if let Some(marker) = &tip_marker {
+        match (&mut file, &mut stdout) {
+            (Some(file), _) => writeln!(file, "{marker}")?,
+            (None, Some(stdout)) => writeln!(stdout, "{marker}")?,
+            (None, None) => {}
+        }
+    }
+    if let Some((height, hash)) = last_block {
+        pb.println(format!("Tip: {hash} at height {height}"));
+    }
+    if let Some(stdout) = &mut stdout {
+        stdout.flush()?;
+    }
+    if let Some((_, log)) = &mut pubkey_dump {
+        log.flush()?;
+    }
+    for log in [&mut opreturn_dump, &mut spend_trace].into_iter().flatten() {
+        log.flush()?;
+    }
+
+    pb.finish_and_clear();
+    eprintln!("P2PK addresses: {}", totals.p2pk_addresses);
+    eprintln!("P2PK coins: {}", totals.p2pk_coins);
+    eprintln!("Blocks processed: {blocks_processed}");
+    eprintln!("Elapsed: {:.1?}", started.elapsed());
+
+    // The rows up to the break are saved, so report where they end
+    if let Some(mismatch) = mismatch {
+        let last_good = match last_good {
+            Some((height, hash)) => format!("{hash} at height {height}"),
+            None => "none".to_owned(),
+        };
+        bail!("Stopped on a height mismatch: {mismatch}. Last good block: {last_good}");
+    }
+
+    Ok(())
+}
+
+/// From BIP34 on, the coinbase encodes the height independently of the node's index. Describes how
+/// the block's coinbase disagrees with `height`, if it does.
+fn bip34_mismatch(block: &Block, height: u64, network: Network) -> Option<String> {
+    if height < network.params().bip34_height as u64 {
+        return None;
+    }
+    let hash = block.block_hash();
+    match block.bip34_block_height() {
+        Ok(coinbase_height) if coinbase_height == height => None,
+        Ok(coinbase_height) => Some(format!(
+            "block {hash} at height {height} has coinbase height {coinbase_height}"
+        )),
+        Err(e) => Some(format!(
+            "no BIP34 height in block {hash} at height {height}: {e}"
+        )),
+    }
+}
+
+/// Parses the block hash and height out of a `# tip=` marker line.
+fn parse_tip_marker(line: &str) -> Option<(BlockHash, u64)> {
+    let mut fields = line.strip_prefix("# tip=")?.split(',');
+    let hash = fields.next()?.parse().ok()?;
+    let height = fields.next()?.strip_prefix("height=")?.parse().ok()?;
+    Some((hash, height))
+}
+
+/// Describes how `block` at `height` fails to build on `last_good`, the block synced before it.
+fn chain_break(last_good: Option<(u64, BlockHash)>, height: u64, block: &Block) -> Option<String> {
+    let (good_height, good_hash) = last_good?;
+    (block.header.prev_blockhash != good_hash).then(|| {
+        format!(
+            "block {} at height {height} builds on {}, not on {good_hash} at height {good_height}",
+            block.block_hash(),
+            block.header.prev_blockhash
+        )
+    })
+}
+
+/// Looks up the output an outpoint refers to, and whether a coinbase created it.
+fn rpc_prevout(rpc: &Client, outpoint: &OutPoint) -> Result<Option<(TxOut, bool)>> {
+    let prev_tx = rpc.get_raw_transaction(&outpoint.txid, None)?;
+    let prev_output = prev_tx.output.get(outpoint.vout as usize).cloned();
+    Ok(prev_output.map(|output| (output, prev_tx.is_coinbase())))
+}
+
+/// Prints the P2PK outputs created and spent between two heights (inclusive) as CSV.
+fn print_p2pk_diff(rpc: &Client, accounting: &Accounting, from: u64, to: u64) -> Result<()> {
+    let lines = p2pk_diff(
+        accounting,
+        from..=to,
+        |height| Ok(rpc.get_block(&rpc.get_block_hash(height)?)?),
+        |outpoint| rpc_prevout(rpc, outpoint),
+    )?;
+
+    println!("Kind,Height,Txid,Vout,Sats");
+    for line in lines {
+        println!("{line}");
+    }
+
+    Ok(())
+}
+
+/// Lists the P2PK outputs created, then those spent, in a range of blocks. An output counts
+/// under the same rules as the sync, so the lines add up to the change in its totals.
+fn p2pk_diff(
+    accounting: &Accounting,
+    heights: impl IntoIterator<Item = u64>,
+    mut get_block: impl FnMut(u64) -> Result<Block>,
+    mut prevout: impl FnMut(&OutPoint) -> Result<Option<(TxOut, bool)>>,
+) -> Result<Vec<String>> {
+    let mut created = vec![];
+    let mut spent = vec![];
+
+    for height in heights {
+        let block = get_block(height)?;
+        let counts = accounting.account_block(&block, &mut Totals::default(), &mut prevout)?;
+        for (outpoint, output) in counts.p2pk_created {
+            created.push(format!(
+                "created,{height},{},{},{}",
+                outpoint.txid,
+                outpoint.vout,
+                output.value.to_sat()
+            ));
+        }
+        for (_, outpoint, prev_output) in counts.p2pk_spent {
+            spent.push(format!(
+                "spent,{height},{},{},{}",
+                outpoint.txid,
+                outpoint.vout,
+                prev_output.value.to_sat()
+            ));
+        }
+    }
+
+    created.extend(spent);
+    Ok(created)
+}
+
+/// Prints the P2PK outputs of the raw transactions in a file as CSV.
+fn scan_txs(path: &str, hex_lines: bool, loose_p2pk: bool) -> Result<()> {
+    let transactions = read_txs(path, hex_lines)?;
+    let lines = p2pk_output_lines(&transactions, loose_p2pk);
+
+    println!("Txid,Vout,Sats");
+    for (line, _) in &lines {
+        println!("{line}");
+    }
+
+    let p2pk_coins: f64 = lines.iter().map(|(_, value)| value.to_btc()).sum();
+    eprintln!(
+        "{} P2PK outputs holding {p2pk_coins} BTC in {} transactions",
+        lines.len(),
+        transactions.len()
+    );
+
+    Ok(())
+}
+
+/// Reads the raw transactions in a file.
+///
+/// The file holds either one hex-encoded transaction per line, or binary transactions each
+/// prefixed with their length as a 4-byte little-endian integer.
+fn read_txs(path: &str, hex_lines: bool) -> Result<Vec<Transaction>> {
+    Ok(if hex_lines {
+        BufReader::new(File::open(path)?)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(consensus::encode::deserialize_hex(line?.trim())?))
+            .collect::<Result<_>>()?
+    } else {
+        let mut bytes = vec![];
+        File::open(path)?.read_to_end(&mut bytes)?;
+
+        let mut transactions = vec![];
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            let (len, tail) = rest
+                .split_first_chunk::<4>()
+                .ok_or_else(|| anyhow!("Truncated length prefix in {path}"))?;
+            let len = u32::from_le_bytes(*len) as usize;
+            if tail.len() < len {
+                bail!("Truncated transaction in {path}");
+            }
+            transactions.push(consensus::deserialize(&tail[..len])?);
+            rest = &tail[len..];
+        }
+        transactions
+    })
+}
+
+/// Lists the P2PK outputs of some transactions as `txid,vout,sats` lines, with their values.
+fn p2pk_output_lines(transactions: &[Transaction], loose_p2pk: bool) -> Vec<(String, Amount)> {
+    let mut lines = vec![];
+    for tx in transactions {
+        let mut txid = None;
+        for (vout, output) in tx.output.iter().enumerate() {
+            if is_p2pk(&output.script_pubkey, loose_p2pk) {
+                let txid = txid.get_or_insert_with(|| tx.compute_txid());
+                let line = format!("{txid},{vout},{}", output.value.to_sat());
+                lines.push((line, output.value));
+            }
+        }
+    }
+    lines
+}
+
+/// Reads a list of hex-encoded public keys, one per line. Blank lines and `#` comments are skipped.
+fn load_burn_list(path: &str) -> Result<HashSet<Vec<u8>>> {
+    let mut keys = HashSet::new();
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        keys.insert(hex::decode(line).map_err(|e| anyhow!("Invalid key {line} in {path}: {e}"))?);
+    }
+    Ok(keys)
+}
+
+/// Opens a pubkey dump for appending, returning the keys it already holds. The dump is a CSV of
+/// hex-encoded P2PK public keys, the height each was first paid at, and its P2PKH address.
+fn open_pubkey_dump(path: &str) -> Result<(HashSet<Vec<u8>>, CsvLog)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    let mut keys = HashSet::new();
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    for line in content.lines().skip(1) {
+        let key = line.split(',').next().unwrap_or_default();
+        keys.insert(hex::decode(key).map_err(|e| anyhow!("Invalid key {key} in {path}: {e}"))?);
+    }
+
+    let mut log = CsvLog::new(file);
+    if content.is_empty() {
+        writeln!(log, "Pubkey,Height,Address")?;
+    }
+    Ok((keys, log))
+}
+
+/// Opens a CSV that rows are appended to as they're found, writing `header` if it's new.
+fn open_csv_log(path: &str, header: &str) -> Result<CsvLog> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    let empty = file.metadata()?.len() == 0;
+
+    let mut log = CsvLog::new(file);
+    if empty {
+        writeln!(log, "{header}")?;
+    }
+    Ok(log)
+}
+
+/// A CSV that rows are appended to, holding them back until it's flushed. Flushing only when the
+/// output is saved means a run that stops in between leaves no rows for the blocks it will process
+/// again, and unlike a `BufWriter`, nothing is written when it's dropped on an error.
+struct CsvLog {
+    file: File,
+    pending: Vec<u8>,
+}
+
+impl CsvLog {
+    fn new(file: File) -> Self {
+        Self {
+            file,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Write for CsvLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.write_all(&self.pending)?;
+        self.pending.clear();
+        self.file.flush()
+    }
+}
+
+/// Writes the output rows as JSON Lines, one object per block keyed by snake_case column names.
+///
+/// Dates are converted to ISO 8601 and numbers are written with full precision.
+fn write_jsonl(path: &Path, out: &[String]) -> Result<()> {
+    let Some((header, rows)) = out.split_first() else {
+        return Ok(());
+    };
+    let keys: Vec<String> = header
+        .split(',')
+        .map(|column| match column {
+            "Total P2PK addresses" => "p2pk_addresses".to_owned(),
+            "Total P2PK coins" => "p2pk_coins".to_owned(),
+            column => column
+                .to_lowercase()
+                .split(|c: char| !c.is_ascii_alphanumeric())
+                .filter(|word| !word.is_empty())
+                .collect::<Vec<_>>()
+                .join("_"),
+        })
+        .collect();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    for row in rows {
+        let object: serde_json::Map<String, serde_json::Value> = keys
+            .iter()
+            .zip(row.split(','))
+            .map(|(key, value)| {
+                let value = if key == "date" {
+                    match parse_date(value) {
+                        Some(date) => json!(date.format(DateFormat::Iso.pattern()).to_string()),
+                        None => json!(value),
+                    }
+                } else if let Ok(int) = value.parse::<i64>() {
+                    json!(int)
+                } else if let Ok(float) = value.parse::<f64>() {
+                    json!(float)
+                } else {
+                    json!(value)
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        writeln!(file, "{}", serde_json::Value::Object(object))?;
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Creates the `blocks` table the rows are mirrored to, unless an earlier run did.
+fn create_blocks_table(db: &Connection) -> Result<()> {
+    db.execute_batch(
+        "CREATE TABLE IF NOT EXISTS blocks (
+            height INTEGER PRIMARY KEY,
+            date TEXT NOT NULL,
+            p2pk_addresses INTEGER NOT NULL,
+            p2pk_coins REAL NOT NULL
+        )",
+    )?;
+    Ok(())
+}
+
+/// Inserts or replaces the rows above `after_height` in the `blocks` table, in one transaction.
+/// Returns the highest height written.
+fn write_sqlite(db: &Connection, out: &[String], after_height: u64) -> Result<u64> {
+    let tx = db.unchecked_transaction()?;
+    let mut max_height = after_height;
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT OR REPLACE INTO blocks (height, date, p2pk_addresses, p2pk_coins)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for row in out {
+            let fields: Vec<&str> = row.split(',').collect();
+            let Some(height) = fields.first().and_then(|h| h.parse::<u64>().ok()) else {
+                continue;
+            };
+            if height <= after_height || fields.len() < 4 {
+                continue;
+            }
+            let addresses: i64 = fields[2].parse()?;
+            let coins: f64 = fields[3].parse()?;
+            insert.execute(params![height, fields[1], addresses, coins])?;
+            max_height = max_height.max(height);
+        }
+    }
+    tx.commit()?;
+
+    Ok(max_height)
+}
+
+/// Parses the value of `column` from the last row written by a previous run, if it had that column.
+fn resume_value<T: FromStr>(last_row: &[(String, String)], column: &str) -> Option<T> {
+    last_row
+        .iter()
+        .find(|(name, _)| name == column)
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Puts the output rows in a canonical form so runs can be diffed byte for byte: sorted by height
+/// with one row per height, and every floating-point column (coins and percentages, as named in
+/// the header) written with a fixed 8 decimal places.
+fn normalize_rows(out: &mut Vec<String>) {
+    let row_height = |line: &String| line.split(',').next().and_then(|h| h.parse::<u64>().ok());
+
+    // Keep the header in place and sort everything after it
+    let start = out
+        .iter()
+        .position(|line| row_height(line).is_some())
+        .unwrap_or(out.len());
+    out[start..].sort_by_key(row_height);
+    let mut rows = out.split_off(start);
+    rows.dedup_by_key(|line| row_height(line));
+
+    let header = out.first().map_or(HEADER, String::as_str);
+    let is_float: Vec<bool> = header
+        .split(',')
+        .map(|column| column.ends_with(" coins") || column.contains('%'))
+        .collect();
+
+    out.extend(rows.into_iter().map(|line| {
+        let mut fields: Vec<String> = line.split(',').map(str::to_owned).collect();
+        for (field, _) in fields.iter_mut().zip(&is_float).filter(|(_, &float)| float) {
+            if let Ok(value) = field.parse::<f64>() {
+                *field = format!("{value:.8}");
+            }
+        }
+        fields.join(",")
+    }));
+}
+
+/// Drops rows repeated by earlier runs that resumed at the last height instead of after it.
+///
+/// A repeat whose totals differ from the original counted that block twice, and every row after it
+/// carries the extra count, so the difference is taken back out of those rows. Only the P2PK totals
+/// can be corrected this way, so a repeat that differs in any other column is an error.
+fn drop_duplicate_rows(out: &mut Vec<String>) -> Result<()> {
+    let mut seen: HashMap<u64, Vec<String>> = HashMap::new();
+    let (mut extra_addresses, mut extra_sats) = (0i64, 0i64);
+    let mut rows = Vec::with_capacity(out.len());
+
+    for line in out.drain(..) {
+        let mut fields: Vec<String> = line.split(',').map(str::to_owned).collect();
+        let Some(height) = fields[0].parse::<u64>().ok() else {
+            rows.push(line);
+            continue;
+        };
+        if fields.len() < 4 {
+            bail!("Row for height {height} is missing the P2PK totals: {line}");
+        }
+        let addresses: i64 = fields[2].parse()?;
+        let sats = (fields[3].parse::<f64>()? * 100_000_000.0).round() as i64;
+        if extra_addresses != 0 || extra_sats != 0 {
+            fields[2] = (addresses - extra_addresses).to_string();
+            fields[3] = ((sats - extra_sats) as f64 / 100_000_000.0).to_string();
+        }
+
+        match seen.entry(height) {
+            Entry::Vacant(entry) => {
+                rows.push(fields.join(","));
+                entry.insert(fields);
+            }
+            Entry::Occupied(entry) => {
+                let original = entry.get();
+                let other_columns_differ = fields.len() != original.len()
+                    || (fields.iter().zip(original).enumerate())
+                        .any(|(i, (field, original))| i != 2 && i != 3 && field != original);
+                if other_columns_differ {
+                    bail!(
+                        "Duplicate row for height {height} differs from the original beyond the P2PK totals, so the rows after it can't be corrected: {line}"
+                    );
+                }
+                let added_addresses = addresses - extra_addresses - original[2].parse::<i64>()?;
+                let added_sats = sats
+                    - extra_sats
+                    - (original[3].parse::<f64>()? * 100_000_000.0).round() as i64;
+                if added_addresses != 0 || added_sats != 0 {
+                    eprintln!(
+                        "WARNING: duplicate row for height {height} counted its block twice, taking {added_addresses} P2PK addresses and {added_sats} sats back out of the rows after it"
+                    );
+                    extra_addresses += added_addresses;
+                    extra_sats += added_sats;
+                }
+            }
+        }
+    }
+
+    *out = rows;
+    Ok(())
+}
+
+/// Looks up the cumulative P2PK addresses and coins at `height` in an output CSV.
+///
+/// Rows repeated by earlier runs throw off the totals after them, so the rows up to `height` are
+/// corrected the same way a resumed sync corrects them before the row is read.
+fn p2pk_total_at_height(path: &str, height: u64) -> Result<Option<(i64, f64)>> {
+    let row_height = |line: &str| line.split(',').next()?.parse::<u64>().ok();
+
+    let mut rows = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.starts_with('#') {
+            continue;
+        }
+        if row_height(&line).is_some_and(|h| h > height) {
+            break;
+        }
+        rows.push(line);
+    }
+    drop_duplicate_rows(&mut rows)?;
+
+    let Some(row) = rows.iter().find(|line| row_height(line) == Some(height)) else {
+        return Ok(None);
+    };
+    let fields: Vec<&str> = row.split(',').collect();
+    let addresses = fields.get(2).and_then(|f| f.parse().ok()).unwrap_or(0);
+    let coins = fields.get(3).and_then(|f| f.parse().ok()).unwrap_or(0.0);
+    Ok(Some((addresses, coins)))
+}
+
+/// Looks up the cumulative P2PK addresses and coins at `height` in the `blocks` table.
+fn p2pk_total_at_height_in_db(db: &Connection, height: u64) -> Result<Option<(i64, f64)>> {
+    let total = db
+        .query_row(
+            "SELECT p2pk_addresses, p2pk_coins FROM blocks WHERE height = ?1",
+            params![height],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+    Ok(total)
+}
+
+/// Number of blocks between subsidy halvings.
+fn halving_interval(network: Network) -> u64 {
+    match network {
+        Network::Regtest => 150,
+        _ => 210_000,
     }
+}
+
+/// Block subsidy in sats at a given height, halving every interval.
+fn block_subsidy(height: u64, network: Network) -> u64 {
+    let halvings = height / halving_interval(network);
+    if halvings >= 64 {
+        0
+    } else {
+        (50 * 100_000_000) >> halvings
+    }
+}
+
+/// Total sats minted by all blocks from genesis up to and including `height`.
+fn mined_supply(height: u64, network: Network) -> u64 {
+    let interval = halving_interval(network);
+    let mut supply = 0;
+    let mut era_start = 0;
+    while era_start <= height && block_subsidy(era_start, network) > 0 {
+        let era_end = (era_start + interval - 1).min(height);
+        supply += (era_end - era_start + 1) * block_subsidy(era_start, network);
+        era_start += interval;
+    }
+    supply
+}
 
+/// P2PK coins as a percentage of all coins mined up to `height`. The genesis output is left out of
+/// the totals, so it's left out of the supply too.
+fn p2pk_supply_share(p2pk_coins: f64, height: u64, network: Network) -> f64 {
+    let supply =
+        Amount::from_sat(mined_supply(height, network) - block_subsidy(0, network)).to_btc();
+    100.0 * p2pk_coins / supply
+}
+
+/// Fails if the P2PK coins exceed everything mined up to `height`.
+fn check_mined_supply(p2pk_coins: f64, height: u64, network: Network) -> Result<()> {
+    let p2pk_sats = (p2pk_coins * 100_000_000.0).round() as u64;
+    let supply = mined_supply(height, network);
+    if p2pk_sats > supply.min(Amount::MAX_MONEY.to_sat()) {
+        bail!("P2PK coins ({p2pk_coins} BTC) exceed the mined supply ({supply} sats) at height {height}");
+    }
     Ok(())
 }
+
+/// Live consumers of per-block records, connected over TCP or a Unix domain socket.
+struct Emitter {
+    listener: Listener,
+    clients: Vec<Box<dyn Write>>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    /// The socket file is removed again when the emitter is dropped
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl Emitter {
+    /// Listens on a Unix socket if `addr` is a path, otherwise on a TCP address.
+    fn bind(addr: &str) -> Result<Self> {
+        let listener = match addr {
+            #[cfg(unix)]
+            path if path.contains('/') => {
+                // A socket left behind by a run that didn't exit cleanly has nobody listening
+                let is_socket = fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket());
+                if is_socket && UnixStream::connect(path).is_err() {
+                    fs::remove_file(path)?;
+                }
+                Listener::Unix(UnixListener::bind(path)?, path.to_owned())
+            }
+            addr => Listener::Tcp(TcpListener::bind(addr)?),
+        };
+        match &listener {
+            Listener::Tcp(listener) => listener.set_nonblocking(true)?,
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.set_nonblocking(true)?,
+        }
+
+        Ok(Self {
+            listener,
+            clients: vec![],
+        })
+    }
+
+    /// Picks up newly connected clients without waiting for any. Streaming is a side channel, so
+    /// a connection that fails is logged and skipped rather than stopping the sync.
+    fn accept(&mut self) -> Result<()> {
+        loop {
+            let client = match &self.listener {
+                Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(true)?;
+                    Ok(Box::new(stream) as Box<dyn Write>)
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => listener.accept().and_then(|(stream, _)| {
+                    stream.set_nonblocking(true)?;
+                    Ok(Box::new(stream) as Box<dyn Write>)
+                }),
+            };
+            match client {
+                Ok(client) => self.clients.push(client),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => {
+                    eprintln!("WARNING: couldn't accept an EMIT_SOCKET client: {e}");
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sends one line to every client, dropping any that have disconnected. Clients are
+    /// non-blocking, so one that doesn't keep up is dropped too rather than stalling the sync.
+    fn send(&mut self, line: &str) {
+        let line = format!("{line}\n");
+        self.clients.retain_mut(|client| {
+            client
+                .write_all(line.as_bytes())
+                .and_then(|_| client.flush())
+                .is_ok()
+        });
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Listener::Unix(_, path) = &self.listener {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accounting::tests::{block, coinbase, p2pk, tx, KEY_G};
+
+    use super::*;
+
+    #[test]
+    fn diff_lists_outputs_created_and_spent_inside_the_range() {
+        let funding = tx(&[], vec![p2pk(KEY_G, 1_000), p2pk(KEY_G, 10)]);
+        let outpoint = OutPoint::new(funding.compute_txid(), 0);
+        let spending = tx(&[outpoint], vec![]);
+        // Heights 10 to 13, with the output created at 11 and spent at 12
+        let blocks = [
+            block(vec![coinbase(vec![])]),
+            block(vec![funding.clone()]),
+            block(vec![spending]),
+            block(vec![coinbase(vec![])]),
+        ];
+        let accounting = Accounting {
+            rules: P2pkRules {
+                min_value: Some(100),
+                ..Default::default()
+            },
+            unique_key_mode: None,
+            track_unspendable: false,
+            details: true,
+        };
+
+        let lines = p2pk_diff(
+            &accounting,
+            10..=13,
+            |height| Ok(blocks[height as usize - 10].clone()),
+            |prev| Ok((*prev == outpoint).then(|| (funding.output[0].clone(), false))),
+        )
+        .unwrap();
+
+        let txid = outpoint.txid;
+        assert_eq!(
+            lines,
+            [
+                format!("created,11,{txid},0,1000"),
+                format!("spent,12,{txid},0,1000")
+            ]
+        );
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn duplicate_rows_are_dropped_and_later_rows_corrected() {
+        let mut out = lines(&[
+            HEADER,
+            "1,d1,1,50",
+            "2,d2,2,100",
+            "2,d2,3,150",
+            "3,d3,4,200.5",
+            "3,d3,4,200.5",
+            "4,d4,6,250.00000001",
+            "4,d4,7,260",
+            "5,d5,8,270",
+        ]);
+
+        drop_duplicate_rows(&mut out).unwrap();
+
+        assert_eq!(
+            out,
+            lines(&[
+                HEADER,
+                "1,d1,1,50",
+                "2,d2,2,100",
+                "3,d3,3,150.5",
+                "4,d4,5,200.00000001",
+                "5,d5,6,210.00000001",
+            ])
+        );
+    }
+
+    #[test]
+    fn duplicate_rows_differing_in_other_columns_are_an_error() {
+        let mut out = lines(&[
+            &format!("{HEADER},Burned sats (OP_RETURN)"),
+            "1,d1,1,50,0",
+            "1,d1,2,100,10",
+        ]);
+
+        assert!(drop_duplicate_rows(&mut out).is_err());
+    }
+
+    #[test]
+    fn emitter_sends_lines_and_drops_clients_that_fall_behind() {
+        let mut emitter = Emitter::bind("127.0.0.1:0").unwrap();
+        let Listener::Tcp(listener) = &emitter.listener else {
+            unreachable!()
+        };
+        let addr = listener.local_addr().unwrap();
+
+        let reader = std::net::TcpStream::connect(addr).unwrap();
+        let _stalled = std::net::TcpStream::connect(addr).unwrap();
+        // Give the connections time to be queued for accepting
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        emitter.accept().unwrap();
+        assert_eq!(emitter.clients.len(), 2);
+
+        emitter.send(r#"{"height":1}"#);
+        let mut line = String::new();
+        BufReader::new(&reader).read_line(&mut line).unwrap();
+        assert_eq!(line, "{\"height\":1}\n");
+
+        // Neither client reads from here on, so both fill up and get dropped without blocking
+        let big = "x".repeat(1 << 20);
+        for _ in 0..256 {
+            emitter.send(&big);
+            if emitter.clients.is_empty() {
+                break;
+            }
+        }
+        assert!(emitter.clients.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn emitter_replaces_a_stale_unix_socket_and_removes_it() {
+        let path = env::temp_dir().join(format!("gabriel-emit-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // A listener dropped without cleaning up leaves its socket file behind
+        drop(UnixListener::bind(path).unwrap());
+        assert!(Path::new(path).exists());
+
+        let emitter = Emitter::bind(path).unwrap();
+        assert!(UnixStream::connect(path).is_ok());
+        drop(emitter);
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn normalize_rows_fixes_every_float_column() {
+        let header = format!(
+            "{HEADER},Segwit adoption %,P2PK % of supply,Total P2SH outputs,Total P2SH coins"
+        );
+        let mut out = lines(&[
+            &header,
+            "2,d2,3,150.30000000000001,12.5,99.9,7,0.1",
+            "1,d1,1,50,0,100,0,0",
+            "2,d2,3,150.3,12.5,99.9,7,0.1",
+        ]);
+
+        normalize_rows(&mut out);
+        let once = out.join("\n");
+        normalize_rows(&mut out);
+
+        assert_eq!(out.join("\n"), once);
+        assert_eq!(
+            out,
+            lines(&[
+                &header,
+                "1,d1,1,50.00000000,0.00000000,100.00000000,0,0.00000000",
+                "2,d2,3,150.30000000,12.50000000,99.90000000,7,0.10000000",
+            ])
+        );
+    }
+
+    #[test]
+    fn subsidy_halves_every_interval() {
+        assert_eq!(block_subsidy(0, Network::Bitcoin), 5_000_000_000);
+        assert_eq!(block_subsidy(209_999, Network::Bitcoin), 5_000_000_000);
+        assert_eq!(block_subsidy(210_000, Network::Bitcoin), 2_500_000_000);
+        assert_eq!(block_subsidy(840_000, Network::Bitcoin), 312_500_000);
+        assert_eq!(block_subsidy(64 * 210_000, Network::Bitcoin), 0);
+        assert_eq!(block_subsidy(150, Network::Regtest), 2_500_000_000);
+    }
+
+    #[test]
+    fn mined_supply_sums_the_subsidies() {
+        assert_eq!(mined_supply(0, Network::Bitcoin), 5_000_000_000);
+        assert_eq!(
+            mined_supply(209_999, Network::Bitcoin),
+            210_000 * 5_000_000_000
+        );
+        assert_eq!(
+            mined_supply(210_000, Network::Bitcoin),
+            210_000 * 5_000_000_000 + 2_500_000_000
+        );
+        // Every sat that will ever be mined, just under the 21 million cap
+        assert_eq!(
+            mined_supply(u64::MAX / 2, Network::Bitcoin),
+            2_099_999_997_690_000
+        );
+        assert!(mined_supply(u64::MAX / 2, Network::Bitcoin) <= Amount::MAX_MONEY.to_sat());
+    }
+
+    #[test]
+    fn mined_supply_check_fires_once_coins_exceed_it() {
+        // 150 BTC is more than the two first blocks mined, but not the three first
+        assert!(check_mined_supply(150.0, 1, Network::Bitcoin).is_err());
+        assert!(check_mined_supply(150.0, 2, Network::Bitcoin).is_ok());
+        assert!(check_mined_supply(100.00000001, 1, Network::Bitcoin).is_err());
+        assert!(check_mined_supply(100.0, 1, Network::Bitcoin).is_ok());
+    }
+
+    #[test]
+    fn p2pk_share_leaves_out_the_genesis_output() {
+        // Every early block paid its subsidy to a P2PK output
+        assert_eq!(p2pk_supply_share(50.0, 1, Network::Bitcoin), 100.0);
+        assert_eq!(p2pk_supply_share(500.0, 10, Network::Bitcoin), 100.0);
+        assert_eq!(p2pk_supply_share(250.0, 10, Network::Bitcoin), 50.0);
+        // At the first halving, the supply excluding genesis is 209,999 * 50 + 25 BTC
+        assert_eq!(
+            p2pk_supply_share(10_499_975.0, 210_000, Network::Bitcoin),
+            100.0
+        );
+    }
+
+    #[test]
+    fn p2pk_total_at_height_finds_rows_in_a_file() {
+        // Rows on both sides of the requested heights, with a tip marker after them
+        let mut content = format!("{HEADER}\n");
+        for height in 1..=2000 {
+            content.push_str(&format!("{height},d,{height},{}.5\n", height * 50));
+        }
+        content.push_str("# tip=00,height=2000,date=d\n");
+        let path = env::temp_dir().join(format!("gabriel-query-{}.csv", std::process::id()));
+        fs::write(&path, content).unwrap();
+        let path = path.to_str().unwrap();
+
+        assert_eq!(p2pk_total_at_height(path, 1).unwrap(), Some((1, 50.5)));
+        assert_eq!(
+            p2pk_total_at_height(path, 1234).unwrap(),
+            Some((1234, 61700.5))
+        );
+        assert_eq!(
+            p2pk_total_at_height(path, 2000).unwrap(),
+            Some((2000, 100000.5))
+        );
+        assert_eq!(p2pk_total_at_height(path, 2001).unwrap(), None);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn scans_p2pk_outputs_of_raw_transactions() {
+        let first = tx(&[], vec![p2pk(KEY_G, 1_000), p2pk(KEY_G, 2_000)]);
+        let second = tx(
+            &[OutPoint::new(first.compute_txid(), 0)],
+            vec![
+                TxOut {
+                    value: Amount::from_sat(500),
+                    script_pubkey: bitcoin::ScriptBuf::new_op_return([]),
+                },
+                p2pk(KEY_G, 300),
+            ],
+        );
+
+        // The same two transactions, hex-encoded one per line and length-prefixed binary
+        let dir = env::temp_dir();
+        let hex_path = dir.join(format!("gabriel-txs-{}.hex", std::process::id()));
+        let binary_path = dir.join(format!("gabriel-txs-{}.bin", std::process::id()));
+        let mut hex = String::new();
+        let mut binary = vec![];
+        for tx in [&first, &second] {
+            hex.push_str(&format!("{}\n\n", consensus::encode::serialize_hex(tx)));
+            let bytes = consensus::serialize(tx);
+            binary.extend((bytes.len() as u32).to_le_bytes());
+            binary.extend(bytes);
+        }
+        fs::write(&hex_path, hex).unwrap();
+        fs::write(&binary_path, binary).unwrap();
+
+        let from_hex = read_txs(hex_path.to_str().unwrap(), true).unwrap();
+        let from_binary = read_txs(binary_path.to_str().unwrap(), false).unwrap();
+        fs::remove_file(hex_path).unwrap();
+        fs::remove_file(binary_path).unwrap();
+        assert_eq!(from_hex, [first.clone(), second.clone()]);
+        assert_eq!(from_binary, from_hex);
+
+        let (first, second) = (first.compute_txid(), second.compute_txid());
+        let lines: Vec<String> = p2pk_output_lines(&from_hex, false)
+            .into_iter()
+            .map(|(line, _)| line)
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                format!("{first},0,1000"),
+                format!("{first},1,2000"),
+                format!("{second},1,300")
+            ]
+        );
+    }
+
+    #[test]
+    fn write_jsonl_writes_one_json_object_per_row() {
+        let out = lines(&[
+            &format!("{HEADER},Burned sats (OP_RETURN),Segwit adoption %"),
+            "1,01/09/2009 02:54:25,1,50,0,0",
+            "2,2009-01-09T02:55:44Z,2,100.5,10,12.5",
+        ]);
+        let path = env::temp_dir().join(format!("gabriel-rows-{}.jsonl", std::process::id()));
+
+        write_jsonl(&path, &out).unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let rows: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                json!({
+                    "height": 1,
+                    "date": "2009-01-09T02:54:25Z",
+                    "p2pk_addresses": 1,
+                    "p2pk_coins": 50,
+                    "burned_sats_op_return": 0,
+                    "segwit_adoption": 0,
+                }),
+                json!({
+                    "height": 2,
+                    "date": "2009-01-09T02:55:44Z",
+                    "p2pk_addresses": 2,
+                    "p2pk_coins": 100.5,
+                    "burned_sats_op_return": 10,
+                    "segwit_adoption": 12.5,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_row_in_the_window_includes_earlier_blocks() {
+        let accounting = Accounting {
+            rules: P2pkRules::default(),
+            unique_key_mode: None,
+            track_unspendable: false,
+            details: false,
+        };
+        let columns = Columns::new(Network::Regtest);
+        // A block a day from 2011-02-02, with the window opening on the third
+        let blocks: Vec<Block> = (0..3)
+            .map(|day| {
+                let mut block = block(vec![coinbase(vec![p2pk(KEY_G, 5_000_000_000)])]);
+                block.header.time = 1296604800 + day * 86400;
+                block
+            })
+            .collect();
+        let window = Window {
+            since: Some(1296604800 + 2 * 86400),
+            until: None,
+        };
+
+        let mut totals = Totals::default();
+        let rows: Vec<Option<String>> = blocks
+            .iter()
+            .zip(1..)
+            .map(|(block, height)| {
+                let sync = sync_block(
+                    &accounting,
+                    &columns,
+                    window,
+                    height,
+                    block,
+                    &mut totals,
+                    |_| Ok(None),
+                );
+                sync.unwrap().1
+            })
+            .collect();
+
+        assert_eq!(
+            rows,
+            [None, None, Some("3,2011-02-04T00:00:00Z,3,150".to_owned())]
+        );
+    }
+
+    #[test]
+    fn csv_and_database_lookups_agree_past_duplicate_rows() {
+        let mut out = lines(&[
+            HEADER,
+            "1,d1,1,50",
+            "2,d2,2,100",
+            "2,d2,3,150",
+            "3,d3,4,200",
+            "# settings=loose_p2pk=false",
+            "# tip=00,height=3,date=d3",
+        ]);
+        let path = env::temp_dir().join(format!("gabriel-lookup-{}.csv", std::process::id()));
+        fs::write(&path, out.join("\n")).unwrap();
+
+        // The database is written from the corrected rows, as a sync does
+        out.retain(|line| !line.starts_with('#'));
+        drop_duplicate_rows(&mut out).unwrap();
+        let db = Connection::open_in_memory().unwrap();
+        create_blocks_table(&db).unwrap();
+        write_sqlite(&db, &out, 0).unwrap();
+
+        let path = path.to_str().unwrap();
+        for height in 1..=4 {
+            assert_eq!(
+                p2pk_total_at_height(path, height).unwrap(),
+                p2pk_total_at_height_in_db(&db, height).unwrap(),
+                "height {height}"
+            );
+        }
+        assert_eq!(p2pk_total_at_height(path, 3).unwrap(), Some((3, 150.0)));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn a_missing_block_breaks_the_chain() {
+        let mut blocks: Vec<Block> = vec![];
+        for time in 0..3 {
+            let mut next = block(vec![coinbase(vec![])]);
+            next.header.time = time;
+            if let Some(prev) = blocks.last() {
+                next.header.prev_blockhash = prev.block_hash();
+            }
+            blocks.push(next);
+        }
+        let walk = |blocks: &[&Block]| -> Vec<Option<String>> {
+            let mut last_good = None;
+            (1..)
+                .zip(blocks)
+                .map(|(height, block)| {
+                    let chain_break = chain_break(last_good, height, block);
+                    last_good = Some((height, block.block_hash()));
+                    chain_break
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            walk(&[&blocks[0], &blocks[1], &blocks[2]]),
+            [None, None, None]
+        );
+        let breaks = walk(&[&blocks[0], &blocks[2]]);
+        assert_eq!(breaks[0], None);
+        assert_eq!(
+            breaks[1],
+            Some(format!(
+                "block {} at height 2 builds on {}, not on {} at height 1",
+                blocks[2].block_hash(),
+                blocks[1].block_hash(),
+                blocks[0].block_hash()
+            ))
+        );
+    }
+
+    #[test]
+    fn tip_markers_are_parsed() {
+        let hash = block(vec![]).block_hash();
+        assert_eq!(
+            parse_tip_marker(&format!("# tip={hash},height=42,date=2009-01-09T02:54:25Z")),
+            Some((hash, 42))
+        );
+        assert_eq!(parse_tip_marker("# settings=loose_p2pk=false"), None);
+    }
+
+    #[test]
+    fn write_sqlite_resumes_after_the_last_height() {
+        let db = Connection::open_in_memory().unwrap();
+        create_blocks_table(&db).unwrap();
+        let rows = |db: &Connection| -> Vec<(u64, i64)> {
+            let mut select = db
+                .prepare("SELECT height, p2pk_addresses FROM blocks ORDER BY height")
+                .unwrap();
+            select
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap()
+        };
+        let mut out = lines(&[HEADER, "1,d1,1,50", "2,d2,2,100"]);
+
+        assert_eq!(write_sqlite(&db, &out, 0).unwrap(), 2);
+        assert_eq!(rows(&db), [(1, 1), (2, 2)]);
+
+        // A checkpoint later, the rows already written are left alone and the new ones added
+        out.extend(lines(&["3,d3,3,150", "4,d4,4,200"]));
+        assert_eq!(write_sqlite(&db, &out, 2).unwrap(), 4);
+        assert_eq!(rows(&db), [(1, 1), (2, 2), (3, 3), (4, 4)]);
+
+        // With nothing new, the height stays put
+        assert_eq!(write_sqlite(&db, &out, 4).unwrap(), 4);
+        assert_eq!(rows(&db).len(), 4);
+
+        // A rerun from scratch replaces rows rather than repeating them
+        assert_eq!(write_sqlite(&db, &out, 0).unwrap(), 4);
+        assert_eq!(rows(&db).len(), 4);
+    }
+
+    #[test]
+    fn bip34_heights_are_checked_from_activation() {
+        let with_script_sig = |hex: &str| {
+            let mut coinbase = coinbase(vec![]);
+            coinbase.input[0].script_sig = bitcoin::ScriptBuf::from_hex(hex).unwrap();
+            let mut block = block(vec![coinbase]);
+            block.header.version = bitcoin::block::Version::TWO;
+            block
+        };
+        // Pushes 500000, little-endian, as the height's first push
+        let block = with_script_sig("0320a10704ffff001d");
+        let hash = block.block_hash();
+
+        assert_eq!(bip34_mismatch(&block, 500000, Network::Bitcoin), None);
+        assert_eq!(
+            bip34_mismatch(&block, 500001, Network::Bitcoin),
+            Some(format!(
+                "block {hash} at height 500001 has coinbase height 500000"
+            ))
+        );
+        // Before activation, the coinbase can push anything
+        assert_eq!(bip34_mismatch(&block, 1000, Network::Bitcoin), None);
+
+        let missing = with_script_sig("");
+        let error = bip34_mismatch(&missing, 500000, Network::Bitcoin).unwrap();
+        assert!(
+            error.starts_with(&format!(
+                "no BIP34 height in block {} at height 500000: ",
+                missing.block_hash()
+            )),
+            "{error}"
+        );
+    }
+}
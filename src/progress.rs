@@ -0,0 +1,74 @@
+//! Sync progress reporting, as a progress bar on a terminal or as plain lines in logs.
+
+use std::time::Duration;
+
+use indicatif::ProgressBar;
+
+/// How progress is shown while syncing.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    /// An interactive bar, with messages printed above it
+    Bar,
+    /// A line for every percent of progress, for logs and CI where the bar can't redraw
+    Plain,
+    /// No progress at all, only messages
+    None,
+}
+
+/// Tracks sync progress, drawing it according to a [`ProgressMode`].
+pub struct Progress {
+    bar: ProgressBar,
+    mode: ProgressMode,
+    last_percent: u64,
+}
+
+impl Progress {
+    pub fn new(len: u64, mode: ProgressMode) -> Self {
+        // A hidden bar still tracks the position and rate, which the ETA is computed from
+        let bar = match mode {
+            ProgressMode::Bar => ProgressBar::new(len),
+            ProgressMode::Plain | ProgressMode::None => {
+                let bar = ProgressBar::hidden();
+                bar.set_length(len);
+                bar
+            }
+        };
+
+        Progress {
+            bar,
+            mode,
+            last_percent: 0,
+        }
+    }
+
+    /// Prints a message, above the bar if there is one. A hidden bar would swallow it, so
+    /// otherwise it goes straight to stderr.
+    pub fn println(&self, message: impl AsRef<str>) {
+        match self.mode {
+            ProgressMode::Bar => self.bar.println(message),
+            ProgressMode::Plain | ProgressMode::None => eprintln!("{}", message.as_ref()),
+        }
+    }
+
+    pub fn inc(&mut self, delta: u64) {
+        self.bar.inc(delta);
+
+        if self.mode == ProgressMode::Plain {
+            let len = self.bar.length().unwrap_or_default().max(1);
+            let position = self.bar.position();
+            let percent = position * 100 / len;
+            if percent > self.last_percent {
+                self.last_percent = percent;
+                eprintln!("Progress: {percent}% ({position}/{len})");
+            }
+        }
+    }
+
+    pub fn eta(&self) -> Duration {
+        self.bar.eta()
+    }
+
+    pub fn finish_and_clear(&self) {
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,456 @@
+//! Classification of scriptPubKeys by output type.
+
+use bitcoin::{
+    hashes::Hash,
+    opcodes::{
+        all::{OP_CHECKMULTISIG, OP_CHECKSIG, OP_PUSHNUM_1, OP_PUSHNUM_16},
+        Opcode,
+    },
+    script::Instruction,
+    Address, Network, PubkeyHash, Script,
+};
+
+/// The kind of output a scriptPubKey locks coins to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptType {
+    /// `<pubkey> OP_CHECKSIG`
+    P2PK,
+    /// `OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG`
+    P2PKH,
+    /// `OP_HASH160 <20 bytes> OP_EQUAL`
+    P2SH,
+    /// `OP_0 <20 bytes>`
+    P2WPKH,
+    /// `OP_0 <32 bytes>`
+    P2WSH,
+    /// `OP_1 <32 bytes>`
+    P2TR,
+    /// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG`
+    Multisig,
+    /// `OP_RETURN ...`, provably unspendable
+    OpReturn,
+    /// Anything else
+    NonStandard,
+}
+
+/// Classifies a scriptPubKey. With `loose_p2pk` set, P2PK keys pushed with `OP_PUSHDATA1` count
+/// as P2PK too (see [`p2pk_pubkey_bytes`]).
+pub fn classify_script(script: &Script, loose_p2pk: bool) -> ScriptType {
+    if p2pk_pubkey_bytes(script, loose_p2pk).is_some() {
+        ScriptType::P2PK
+    } else if script.is_p2pkh() {
+        ScriptType::P2PKH
+    } else if script.is_p2sh() {
+        ScriptType::P2SH
+    } else if script.is_p2wpkh() {
+        ScriptType::P2WPKH
+    } else if script.is_p2wsh() {
+        ScriptType::P2WSH
+    } else if script.is_p2tr() {
+        ScriptType::P2TR
+    } else if is_bare_multisig(script).is_some() {
+        ScriptType::Multisig
+    } else if script.is_op_return() {
+        ScriptType::OpReturn
+    } else {
+        ScriptType::NonStandard
+    }
+}
+
+/// Returns the public key bytes pushed by a P2PK script.
+///
+/// The strict form is `OP_PUSHBYTES_33/65 <pubkey> OP_CHECKSIG`. With `loose` set, a key pushed
+/// with a non-minimal `OP_PUSHDATA1` is also accepted, as long as it has a plausible key prefix.
+pub fn p2pk_pubkey_bytes(script: &Script, loose: bool) -> Option<&[u8]> {
+    let bytes = script.as_bytes();
+    match bytes {
+        [0x21, key @ .., last] | [0x41, key @ .., last]
+            if key.len() == bytes[0] as usize && *last == OP_CHECKSIG.to_u8() =>
+        {
+            Some(key)
+        }
+        [0x4c, len, key @ .., last]
+            if loose && key.len() == *len as usize && *last == OP_CHECKSIG.to_u8() =>
+        {
+            match (key.len(), key.first()) {
+                (33, Some(0x02 | 0x03)) | (65, Some(0x04 | 0x06 | 0x07)) => Some(key),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn is_p2pk(script: &Script, loose: bool) -> bool {
+    classify_script(script, loose) == ScriptType::P2PK
+}
+
+/// Returns `(m, n)` for a bare `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` script.
+///
+/// The script must push exactly `n` 33- or 65-byte keys, and `m` can't exceed `n`.
+pub fn is_bare_multisig(script: &Script) -> Option<(u8, u8)> {
+    let small_int = |op: &Opcode| {
+        let code = op.to_u8();
+        (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8())
+            .contains(&code)
+            .then(|| code - OP_PUSHNUM_1.to_u8() + 1)
+    };
+
+    let instructions = script.instructions().collect::<Result<Vec<_>, _>>().ok()?;
+    let [Instruction::Op(m), keys @ .., Instruction::Op(n), Instruction::Op(OP_CHECKMULTISIG)] =
+        instructions.as_slice()
+    else {
+        return None;
+    };
+    let (m, n) = (small_int(m)?, small_int(n)?);
+
+    let all_keys = keys
+        .iter()
+        .all(|key| matches!(key, Instruction::PushBytes(key) if matches!(key.len(), 33 | 65)));
+
+    (all_keys && keys.len() == n as usize && m <= n).then_some((m, n))
+}
+
+/// Returns the data pushed after the `OP_RETURN` of a null data script, concatenated.
+///
+/// A bare `OP_RETURN` has an empty payload. The payload ends at the first opcode that isn't a
+/// push, or at a push that runs past the end of the script.
+pub fn op_return_payload(script: &Script) -> Option<Vec<u8>> {
+    if !script.is_op_return() {
+        return None;
+    }
+
+    let mut payload = Vec::new();
+    for instruction in script.instructions().skip(1) {
+        match instruction {
+            Ok(Instruction::PushBytes(data)) => payload.extend_from_slice(data.as_bytes()),
+            _ => break,
+        }
+    }
+    Some(payload)
+}
+
+/// Returns the address an output pays to on `network`, if it has one.
+///
+/// P2PK outputs have no address of their own, so like block explorers this gives them the P2PKH
+/// address of their key.
+pub fn encode_address(script: &Script, loose_p2pk: bool, network: Network) -> Option<String> {
+    let address = match p2pk_pubkey_bytes(script, loose_p2pk) {
+        Some(key) => Address::p2pkh(PubkeyHash::hash(key), network),
+        None => Address::from_script(script, network).ok()?,
+    };
+    Some(address.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{blockdata::constants::genesis_block, ScriptBuf};
+
+    use super::*;
+
+    fn script(hex: &str) -> ScriptBuf {
+        ScriptBuf::from_hex(hex).unwrap()
+    }
+
+    /// Output of the genesis coinbase, paying 50 BTC to an uncompressed key
+    const GENESIS_P2PK: &str = "4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac";
+
+    #[test]
+    fn classifies_each_script_type() {
+        let cases = [
+            (GENESIS_P2PK, ScriptType::P2PK),
+            (
+                "210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac",
+                ScriptType::P2PK,
+            ),
+            (
+                "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+                ScriptType::P2PKH,
+            ),
+            (
+                "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87",
+                ScriptType::P2SH,
+            ),
+            (
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                ScriptType::P2WPKH,
+            ),
+            (
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+                ScriptType::P2WSH,
+            ),
+            (
+                "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+                ScriptType::P2TR,
+            ),
+            (
+                "51210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f8179851ae",
+                ScriptType::Multisig,
+            ),
+            ("6a0b68656c6c6f20776f726c64", ScriptType::OpReturn),
+            ("51", ScriptType::NonStandard),
+        ];
+        for (hex, expected) in cases {
+            assert_eq!(classify_script(&script(hex), false), expected, "{hex}");
+        }
+    }
+
+    #[test]
+    fn is_p2pk_delegates_to_classify_script() {
+        assert!(is_p2pk(&script(GENESIS_P2PK), false));
+        assert!(!is_p2pk(
+            &script("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac"),
+            false
+        ));
+    }
+
+    #[test]
+    fn p2pk_requires_checksig() {
+        // The genesis key push without its trailing OP_CHECKSIG
+        let bare_key = &GENESIS_P2PK[..GENESIS_P2PK.len() - 2];
+        assert_eq!(
+            classify_script(&script(bare_key), false),
+            ScriptType::NonStandard
+        );
+        assert_eq!(
+            p2pk_pubkey_bytes(&script(GENESIS_P2PK), false)
+                .unwrap()
+                .len(),
+            65
+        );
+    }
+
+    #[test]
+    fn loose_p2pk_accepts_pushdata1_keys() {
+        // The genesis key pushed with OP_PUSHDATA1 instead of OP_PUSHBYTES_65
+        let pushdata1 = format!("4c{GENESIS_P2PK}");
+        assert_eq!(
+            classify_script(&script(&pushdata1), false),
+            ScriptType::NonStandard
+        );
+        assert_eq!(classify_script(&script(&pushdata1), true), ScriptType::P2PK);
+        assert_eq!(
+            p2pk_pubkey_bytes(&script(&pushdata1), true),
+            p2pk_pubkey_bytes(&script(GENESIS_P2PK), false)
+        );
+
+        let compressed = "4c210279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac";
+        assert!(is_p2pk(&script(compressed), true));
+
+        // A 33-byte push without a compressed key prefix isn't taken for a key
+        let bad_prefix = "4c210579be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798ac";
+        assert!(!is_p2pk(&script(bad_prefix), true));
+    }
+
+    #[test]
+    fn p2tr_needs_a_32_byte_program() {
+        // BIP86 key-path output for the first test vector's key
+        let taproot = "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c";
+        assert_eq!(classify_script(&script(taproot), false), ScriptType::P2TR);
+
+        // OP_1 followed by a 31- or 33-byte push isn't taproot
+        let short = "511fa60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc68";
+        let long = "5121a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c4c";
+        assert_ne!(classify_script(&script(short), false), ScriptType::P2TR);
+        assert_ne!(classify_script(&script(long), false), ScriptType::P2TR);
+    }
+
+    // Compressed keys for the generator points G, 2G and 3G
+    const KEY_1: &str = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const KEY_2: &str = "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5";
+    const KEY_3: &str = "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9";
+
+    /// Builds `OP_m <keys>... OP_n OP_CHECKMULTISIG`.
+    fn multisig(m: u8, keys: &[&str], n: u8) -> ScriptBuf {
+        let pushes: String = keys.iter().map(|key| format!("21{key}")).collect();
+        script(&format!("{:02x}{pushes}{:02x}ae", 0x50 + m, 0x50 + n))
+    }
+
+    #[test]
+    fn bare_multisig_reports_m_and_n() {
+        assert_eq!(is_bare_multisig(&multisig(1, &[KEY_1], 1)), Some((1, 1)));
+        assert_eq!(
+            is_bare_multisig(&multisig(2, &[KEY_1, KEY_2, KEY_3], 3)),
+            Some((2, 3))
+        );
+        assert_eq!(
+            is_bare_multisig(&multisig(3, &[KEY_1, KEY_2, KEY_3], 3)),
+            Some((3, 3))
+        );
+    }
+
+    #[test]
+    fn malformed_multisig_is_rejected() {
+        // m greater than n
+        assert_eq!(is_bare_multisig(&multisig(2, &[KEY_1], 1)), None);
+        // n doesn't match the number of keys pushed
+        assert_eq!(is_bare_multisig(&multisig(1, &[KEY_1, KEY_2], 3)), None);
+        // A 20-byte push isn't a key
+        let hash_push = script(&format!("5114{}51ae", &KEY_1[2..42]));
+        assert_eq!(is_bare_multisig(&hash_push), None);
+        // OP_CHECKMULTISIGVERIFY rather than OP_CHECKMULTISIG
+        let verify = script(&format!("5121{KEY_1}51af"));
+        assert_eq!(is_bare_multisig(&verify), None);
+        assert_eq!(classify_script(&verify, false), ScriptType::NonStandard);
+    }
+
+    #[test]
+    fn op_return_payload_concatenates_pushes() {
+        let data = "ab".repeat(80);
+        assert_eq!(
+            op_return_payload(&script(&format!("6a4c50{data}"))),
+            Some(vec![0xab; 80])
+        );
+        assert_eq!(op_return_payload(&script("6a")), Some(vec![]));
+        assert_eq!(
+            op_return_payload(&script("6a02aabb01cc")),
+            Some(vec![0xaa, 0xbb, 0xcc])
+        );
+        // The payload stops at a non-push opcode or a push past the end of the script
+        assert_eq!(op_return_payload(&script("6a01aa75")), Some(vec![0xaa]));
+        assert_eq!(op_return_payload(&script("6a01aa05bb")), Some(vec![0xaa]));
+        assert_eq!(op_return_payload(&script(GENESIS_P2PK)), None);
+    }
+
+    #[test]
+    fn p2sh_needs_the_canonical_23_byte_form() {
+        let hash = "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb";
+        assert_eq!(
+            classify_script(&script(&format!("a914{hash}87")), false),
+            ScriptType::P2SH
+        );
+
+        let near_misses = [
+            // OP_EQUALVERIFY instead of OP_EQUAL
+            format!("a914{hash}88"),
+            // 19- and 21-byte hashes
+            format!("a913{}87", &hash[2..]),
+            format!("a915{hash}0087"),
+            // OP_SHA256 instead of OP_HASH160
+            format!("a814{hash}87"),
+            // Trailing opcode
+            format!("a914{hash}8775"),
+        ];
+        for hex in near_misses {
+            assert_ne!(
+                classify_script(&script(&hex), false),
+                ScriptType::P2SH,
+                "{hex}"
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_addresses_per_network() {
+        let cases = [
+            // P2PK gets the P2PKH address of its key
+            (
+                GENESIS_P2PK,
+                Network::Bitcoin,
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            ),
+            (
+                GENESIS_P2PK,
+                Network::Testnet,
+                "mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt",
+            ),
+            (
+                "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac",
+                Network::Bitcoin,
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            ),
+            (
+                "a914b472a266d0bd89c13706a4132ccfb16f7c3b9fcb87",
+                Network::Bitcoin,
+                "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+            ),
+            (
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                Network::Bitcoin,
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            ),
+            (
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                Network::Testnet,
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+            (
+                "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+                Network::Bitcoin,
+                "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+            ),
+        ];
+        for (hex, network, expected) in cases {
+            assert_eq!(
+                encode_address(&script(hex), false, network).as_deref(),
+                Some(expected),
+                "{hex} on {network}"
+            );
+        }
+
+        assert_eq!(encode_address(&script("6a"), false, Network::Bitcoin), None);
+    }
+
+    /// Coinbase output keys of early mainnet blocks. Every coinbase through block 200 pays a
+    /// single uncompressed key with `OP_PUSHBYTES_65 <key> OP_CHECKSIG`, as the committed out.csv
+    /// shows: one more P2PK address per block until the first spend at block 170.
+    const EARLY_COINBASE_KEYS: [(u64, &str); 5] = [
+        (0, "04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f"),
+        (1, "0496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858ee"),
+        (2, "047211a824f55b505228e4c3d5194c1fcfaa15a456abdf37f9b9d97a4040afc073dee6c89064984f03385237d92167c13e236446b417ab79a0fcae412ae3316b77"),
+        (3, "0494b9d3e76c5b1629ecf97fff95d7a4bbdac87cc26099ada28066c6ff1eb9191223cd897194a08d0c2726c5747f1db49e8cf90e75dc3e3550ae9b30086f3cd5aa"),
+        (9, "0411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3"),
+    ];
+
+    #[test]
+    fn early_coinbase_outputs_are_p2pk() {
+        let genesis = genesis_block(Network::Bitcoin);
+        assert_eq!(
+            genesis.txdata[0].output[0].script_pubkey,
+            script(&format!("41{}ac", EARLY_COINBASE_KEYS[0].1))
+        );
+        for (height, key) in EARLY_COINBASE_KEYS {
+            let script = script(&format!("41{key}ac"));
+            assert!(is_p2pk(&script, false), "block {height}");
+            assert_eq!(
+                p2pk_pubkey_bytes(&script, false),
+                Some(&hex::decode(key).unwrap()[..])
+            );
+            assert!(
+                bitcoin::PublicKey::from_slice(&hex::decode(key).unwrap()).is_ok(),
+                "block {height}"
+            );
+        }
+    }
+
+    /// Shapes a coinbase output paying a key can take, and whether each counts as P2PK strictly
+    /// and with `LOOSE_P2PK`. Only the minimal push followed by `OP_CHECKSIG` is standard. A key
+    /// pushed with `OP_PUSHDATA1` is intentionally left out by default, as it's a non-minimal push
+    /// no early miner used, and anything else can't be spent with a signature for the key alone.
+    #[test]
+    fn coinbase_script_shapes() {
+        let key = EARLY_COINBASE_KEYS[1].1;
+        let shapes = [
+            (format!("41{key}ac"), true, true),
+            (format!("21{KEY_1}ac"), true, true),
+            // OP_PUSHDATA1 pushes of a plausible key
+            (format!("4c41{key}ac"), false, true),
+            (format!("4c21{KEY_1}ac"), false, true),
+            // No OP_CHECKSIG, or OP_CHECKSIGVERIFY instead
+            (format!("41{key}"), false, false),
+            (format!("41{key}ad"), false, false),
+            // Push length not matching the key
+            (format!("41{}ac", &key[2..]), false, false),
+            (format!("4c40{}ac", &key[2..]), false, false),
+            // Key prefix that no encoding uses
+            (format!("4c41{}{}ac", "05", &key[2..]), false, false),
+            // The key's hash rather than the key
+            (format!("76a914{}88ac", "00".repeat(20)), false, false),
+        ];
+        for (hex, strict, loose) in shapes {
+            let script = script(&hex);
+            assert_eq!(is_p2pk(&script, false), strict, "{hex}");
+            assert_eq!(is_p2pk(&script, true), loose, "{hex}");
+        }
+    }
+}